@@ -1,5 +1,173 @@
-use crate::types::SshKey;
+use crate::agent_client::{AgentIdentity, SSH_AUTH_SOCK_ENV, list_agent_identities};
+use crate::keywords::is_ssh_config_keyword;
+use crate::pubkey::PublicKey;
+use crate::types::{AgentKeys, AgentSocket, FingerprintHash, SkipReason, SkippedIdentity, SshKey};
+use anyhow::Context;
 use base64::{Engine as _, engine::general_purpose};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// A source of SSH identities that can be turned into `SshKey` values.
+///
+/// The live agent is the usual source, but keys can also be read from public
+/// key files or held in memory, so callers can choose where keys come from.
+pub trait AgentSource {
+    /// Return the identities (public key blob and comment) held by this source.
+    fn identities(&self) -> anyhow::Result<Vec<AgentIdentity>>;
+
+    /// The agent socket the identities are served from, if this source is an agent.
+    fn agent_socket(&self) -> Option<AgentSocket> {
+        None
+    }
+}
+
+/// A running SSH agent, either at an explicit socket or the one named by `SSH_AUTH_SOCK`.
+#[derive(Debug, Clone, Default)]
+pub struct LiveAgent {
+    socket: Option<PathBuf>,
+}
+
+impl LiveAgent {
+    /// The agent named by `SSH_AUTH_SOCK`.
+    pub fn from_env() -> Self {
+        Self::default()
+    }
+
+    /// The agent listening on `socket`, regardless of `SSH_AUTH_SOCK`.
+    pub fn with_socket(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: Some(socket.into()),
+        }
+    }
+
+    /// The explicitly selected socket, if any.
+    pub fn socket(&self) -> Option<&Path> {
+        self.socket.as_deref()
+    }
+}
+
+impl AgentSource for LiveAgent {
+    fn identities(&self) -> anyhow::Result<Vec<AgentIdentity>> {
+        Ok(list_agent_identities(self.socket())?)
+    }
+
+    fn agent_socket(&self) -> Option<AgentSocket> {
+        // Written into the ssh config later, so it must not depend on our working directory.
+        let absolute = |socket: PathBuf| std::path::absolute(&socket).unwrap_or(socket);
+        match self.socket {
+            Some(ref socket) => Some(AgentSocket::Path(absolute(socket.clone()))),
+            None => std::env::var_os(SSH_AUTH_SOCK_ENV)
+                .filter(|s| !s.is_empty())
+                .map(|s| AgentSocket::Env(absolute(PathBuf::from(s)))),
+        }
+    }
+}
+
+/// Identities read from OpenSSH public key lines (`<type> <base64> [comment]`),
+/// as found in `.pub` files or printed by `ssh-add -L`.
+#[derive(Debug, Clone, Default)]
+pub struct PublicKeyList {
+    identities: Vec<AgentIdentity>,
+}
+
+impl PublicKeyList {
+    /// Read public keys from each of the given files.
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> anyhow::Result<Self> {
+        let mut list = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Failed to open public key file {}", path.display()))?;
+            list.read_lines(std::io::BufReader::new(file), &path.display().to_string())?;
+        }
+        Ok(list)
+    }
+
+    /// Read public keys from a reader, e.g. `ssh-add -L` output piped to stdin.
+    pub fn from_reader<R: BufRead>(reader: R, origin: &str) -> anyhow::Result<Self> {
+        let mut list = Self::default();
+        list.read_lines(reader, origin)?;
+        Ok(list)
+    }
+
+    /// Append the identities of another list.
+    pub fn extend(&mut self, other: PublicKeyList) {
+        self.identities.extend(other.identities);
+    }
+
+    fn read_lines<R: BufRead>(&mut self, reader: R, origin: &str) -> anyhow::Result<()> {
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read {}", origin))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let identity = parse_public_key_line(line)
+                .with_context(|| format!("{}:{}: invalid public key line", origin, idx + 1))?;
+            self.identities.push(identity);
+        }
+        Ok(())
+    }
+}
+
+impl AgentSource for PublicKeyList {
+    fn identities(&self) -> anyhow::Result<Vec<AgentIdentity>> {
+        Ok(self.identities.clone())
+    }
+}
+
+/// Parse a single `<type> <base64> [comment]` line.
+///
+/// The comment is everything after the key data, so nickname comments such as
+/// `My Server <dev@host>` keep their spaces.
+fn parse_public_key_line(line: &str) -> anyhow::Result<AgentIdentity> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let key_type = parts.next().unwrap_or_default();
+    let data = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("missing key data"))?;
+    let comment = parts.next().unwrap_or_default().trim();
+
+    let blob = general_purpose::STANDARD
+        .decode(data)
+        .context("key data is not valid base64")?;
+    let public_key = PublicKey::parse(&blob).context("key data is not a valid public key")?;
+    if public_key.algorithm != key_type {
+        anyhow::bail!("key type {} does not match key data", key_type);
+    }
+
+    Ok(AgentIdentity {
+        blob,
+        comment: comment.to_string(),
+    })
+}
+
+/// An in-memory list of identities, useful for tests and tooling.
+#[derive(Debug, Clone, Default)]
+pub struct FakeAgent {
+    pub identities: Vec<AgentIdentity>,
+}
+
+impl FakeAgent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an identity with the given public key blob and comment.
+    pub fn with_identity(mut self, blob: Vec<u8>, comment: &str) -> Self {
+        self.identities.push(AgentIdentity {
+            blob,
+            comment: comment.to_string(),
+        });
+        self
+    }
+}
+
+impl AgentSource for FakeAgent {
+    fn identities(&self) -> anyhow::Result<Vec<AgentIdentity>> {
+        Ok(self.identities.clone())
+    }
+}
 
 /// Extract text enclosed in `<` and `>` from the given string.
 ///
@@ -14,115 +182,726 @@ fn extract_chevron_text(s: &str) -> Option<&str> {
     Some(&s[start + 1..end])
 }
 
-/// Parse an SSH identity comment of the form `user@host`.
+/// Directives we always write ourselves, or that would break the generated
+/// block structure, and so can't be set from a comment option.
+const RESERVED_OPTIONS: &[&str] = &[
+    "host",
+    "match",
+    "include",
+    "hostname",
+    "user",
+    "identityfile",
+    "certificatefile",
+    "identityagent",
+    "identitiesonly",
+    // These run commands or load code, which a key comment must never be able to do.
+    "proxycommand",
+    "localcommand",
+    "permitlocalcommand",
+    "knownhostscommand",
+    "remotecommand",
+    "pkcs11provider",
+    "securitykeyprovider",
+    "xauthlocation",
+];
+
+/// Directives that take more than one argument. Option values can't contain
+/// spaces, so these can't be given in full from a comment.
+const MULTI_ARGUMENT_OPTIONS: &[&str] = &["localforward", "remoteforward"];
+
+/// Reject values that can't be written into ssh_config as a plain word: a
+/// quote changes how ssh splits the line, a leading `#` turns the rest of
+/// the line into a comment and a control character may end the line.
+fn check_config_value(field: &str, value: &str) -> Result<(), SkipReason> {
+    if value.contains(['"', '\'']) || value.starts_with('#') || value.chars().any(char::is_control) {
+        return Err(SkipReason::UnsafeValue {
+            field: field.to_string(),
+            value: value.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Most hosts a single comment may expand to.
+const MAX_COMMENT_TARGETS: usize = 64;
+
+/// One `user@host[:port]` a key is used for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    user: String,
+    host: String,
+    port: Option<u16>,
+}
+
+/// What was understood from an identity comment.
+#[derive(Debug, Default)]
+struct ParsedComment {
+    name: Option<String>,
+    targets: Vec<Target>,
+    /// Hosts given as `!host`, excluded from every pattern of the key.
+    negated_hosts: Vec<String>,
+    proxy_jump: Option<String>,
+    options: Vec<(String, String)>,
+}
+
+/// Parse an SSH identity comment of the form `user@host` or `Name <user@host>`.
+///
+/// Several targets may be given, separated by commas and optionally using
+/// brace expansion: `deploy <git@a.example, git@b.example>` and
+/// `git@{a,b}.example` both yield `a.example` and `b.example`.
 ///
-/// Returns `Some((user, host))` if the comment contains exactly one `@`,
-/// contains no whitespace, and both `user` and `host` are non-empty.
-/// Otherwise returns `None`.
-fn process_ssh_comment(comment: &str) -> Option<(String, String, Option<String>)> {
-    let mut user_host = comment.trim();
-    let mut name: Option<String> = None;
+/// Hosts may be ssh_config patterns using `*` and `?`, and `!host` (or
+/// `user@!host`) excludes a host from the others, e.g.
+/// `ops@*.prod.example.com, !db.prod.example.com`.
+///
+/// Each host may carry a port (`user@host:2222`), and the targets may be
+/// followed by whitespace-separated `key=value` options, e.g.
+/// `Build Box <ci@build.internal:2222 via=bastion ForwardAgent=no>`.
+/// `via=` and `jump=` become `ProxyJump`, `port=` sets `Port` for targets
+/// without their own port, and any other option is passed through as an
+/// ssh_config directive.
+///
+/// Every target must contain exactly one `@` and both `user` and `host` must
+/// be non-empty, and no field may contain anything that would change the
+/// meaning of the generated config (see [`check_config_value`]). Otherwise
+/// returns the reason the comment was rejected.
+fn process_ssh_comment(comment: &str) -> Result<ParsedComment, SkipReason> {
+    let mut inner = comment.trim();
+    let mut parsed = ParsedComment::default();
 
     if comment.contains('<') || comment.contains('>') {
         // Must have exactly one '<' and one '>' and the '>' must come after '<'.
         if comment.matches('<').count() != 1 || comment.matches('>').count() != 1 {
-            return None;
+            return Err(SkipReason::UnbalancedChevrons);
         }
 
-        user_host = extract_chevron_text(&comment)?; // Return None as extraction of <> failed.
-        name = comment.find('<').map(|idx| {
+        inner = extract_chevron_text(comment).ok_or(SkipReason::UnbalancedChevrons)?;
+        parsed.name = comment.find('<').map(|idx| {
             comment[..idx].trim().replace( " ", "_")
         });
     }
 
-    // exactly one @
-    if user_host.matches('@').count() != 1 {
-        return None;
+    let mut words = inner.split_whitespace().peekable();
+    // The target list runs on past whitespace after a comma or inside braces.
+    let mut target_list = words.next().unwrap_or_default().to_string();
+    while let Some(next) = words.peek() {
+        let open_braces = target_list.matches('{').count() > target_list.matches('}').count();
+        if !(open_braces || target_list.ends_with(',') || next.starts_with(',')) {
+            break;
+        }
+        target_list.push_str(next);
+        words.next();
+    }
+
+    let mut expanded = Vec::new();
+    for target in split_top_level(&target_list) {
+        if target.is_empty() {
+            return Err(SkipReason::InvalidTargets("empty entry in host list".to_string()));
+        }
+        expanded.extend(expand_braces(target)?);
+        if expanded.len() > MAX_COMMENT_TARGETS {
+            return Err(SkipReason::InvalidTargets(format!(
+                "more than {} hosts",
+                MAX_COMMENT_TARGETS
+            )));
+        }
+    }
+
+    let single = expanded.len() == 1;
+    for user_host in &expanded {
+        // `!host` or `user@!host` excludes a host from the key's patterns.
+        if let Some(negated) = user_host
+            .strip_prefix('!')
+            .or_else(|| user_host.split_once("@!").map(|(_, host)| host))
+        {
+            if negated.is_empty() || negated.contains(['!', '@', '[', ']']) {
+                return Err(SkipReason::InvalidHost(format!("bad negated host `{}`", user_host)));
+            }
+            check_config_value("negated host", negated)?;
+            if !parsed.negated_hosts.iter().any(|host| host == negated) {
+                parsed.negated_hosts.push(negated.to_string());
+            }
+            continue;
+        }
+        // exactly one @
+        match user_host.matches('@').count() {
+            0 if single && inner.contains('@') => return Err(SkipReason::Whitespace),
+            0 => return Err(SkipReason::NoAt),
+            1 => {}
+            _ => return Err(SkipReason::MultipleAt),
+        }
+        // split and ensure non-empty parts
+        let (user, host) = user_host.split_once('@').ok_or(SkipReason::NoAt)?;
+        let (host, port) = split_host_port(host)?;
+        if user.is_empty() || host.is_empty() {
+            return Err(SkipReason::EmptyUserOrHost);
+        }
+        check_config_value("user", user)?;
+        check_config_value("host", host)?;
+        if host.contains('!') {
+            return Err(SkipReason::InvalidHost(format!("`!` inside `{}`", host)));
+        }
+        let target = Target {
+            user: user.to_string(),
+            host: host.to_string(),
+            port: port.map(parse_port).transpose()?,
+        };
+        if !parsed.targets.contains(&target) {
+            parsed.targets.push(target);
+        }
+    }
+
+    if let Some(ref name) = parsed.name {
+        check_config_value("name", name)?;
+    }
+
+    let mut default_port = None;
+    for word in words {
+        let (key, value) = word
+            .split_once('=')
+            .ok_or_else(|| SkipReason::InvalidOption(format!("`{}`, expected key=value", word)))?;
+        if value.is_empty() {
+            return Err(SkipReason::InvalidOption(format!("`{}` has no value", key)));
+        }
+        let lower = key.to_ascii_lowercase();
+        if !is_ssh_config_keyword(&lower) && lower != "via" && lower != "jump" {
+            return Err(SkipReason::InvalidOption(format!("`{}` is not a keyword", key)));
+        }
+        check_config_value(key, value)?;
+        match lower.as_str() {
+            "via" | "jump" | "proxyjump" if parsed.proxy_jump.is_none() => {
+                parsed.proxy_jump = Some(value.to_string())
+            }
+            "port" if default_port.is_none() => default_port = Some(parse_port(value)?),
+            "via" | "jump" | "proxyjump" | "port" => {
+                return Err(SkipReason::InvalidOption(format!("`{}` given twice", key)));
+            }
+            lower if RESERVED_OPTIONS.contains(&lower) => {
+                return Err(SkipReason::InvalidOption(format!("`{}` can't be overridden", key)));
+            }
+            lower if MULTI_ARGUMENT_OPTIONS.contains(&lower) => {
+                return Err(SkipReason::InvalidOption(format!(
+                    "`{}` needs more than one argument",
+                    key
+                )));
+            }
+            _ => parsed.options.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    if parsed.targets.is_empty() {
+        // A `Host` line of only negations never matches anything.
+        return Err(SkipReason::InvalidTargets("only negated hosts".to_string()));
     }
-    // no whitespace anywhere
-    if user_host.chars().any(|c| c.is_whitespace()) {
-        return None;
+    for target in &mut parsed.targets {
+        target.port = target.port.or(default_port);
     }
-    // split and ensure non-empty parts
-    let (user, host) = user_host.split_once('@')?;
-    if user.is_empty() || host.is_empty() {
-        return None;
+    Ok(parsed)
+}
+
+/// Split `s` on commas that are not inside braces, trimming each part.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(s[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
     }
-    Some((user.to_string(), host.to_string(), name))
+    parts.push(s[start..].trim());
+    parts
 }
 
-/// Extract the SSH key type from an SSH public key blob.
+/// Expand shell-style braces, e.g. `git@{a,b}.example` into
+/// `git@a.example` and `git@b.example`. Braces may be nested.
+fn expand_braces(s: &str) -> Result<Vec<String>, SkipReason> {
+    let unbalanced = || SkipReason::InvalidTargets(format!("unbalanced braces in `{}`", s));
+
+    let Some(open) = s.find('{') else {
+        if s.contains('}') {
+            return Err(unbalanced());
+        }
+        return Ok(vec![s.to_string()]);
+    };
+    let mut depth = 0usize;
+    let mut close = None;
+    for (idx, c) in s[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(open + idx);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = close.ok_or_else(unbalanced)?;
+
+    let (prefix, suffix) = (&s[..open], &s[close + 1..]);
+    let mut expanded = Vec::new();
+    for alternative in split_top_level(&s[open + 1..close]) {
+        expanded.extend(expand_braces(&format!("{}{}{}", prefix, alternative, suffix))?);
+        if expanded.len() > MAX_COMMENT_TARGETS {
+            return Err(SkipReason::InvalidTargets(format!(
+                "more than {} hosts",
+                MAX_COMMENT_TARGETS
+            )));
+        }
+    }
+    Ok(expanded)
+}
+
+/// Split `host:port` into host and port.
 ///
-/// The blob is expected to start with a big-endian `u32` length followed by
-/// a UTF-8 string containing the key type (e.g. `"ssh-rsa"`). Returns
-/// `Some(type)` on success, otherwise `None`.
-fn get_ssh_key_type(blob: &[u8]) -> Option<String> {
-    if blob.len() < 4 {
-        return None;
+/// The host may be bracketed, as in `[2001:db8::1]:2222` or `[host]:2222`;
+/// the brackets are dropped since ssh_config takes IPv6 addresses bare.
+/// An unbracketed host with more than one `:` is an IPv6 address without a port.
+fn split_host_port(host: &str) -> Result<(&str, Option<&str>), SkipReason> {
+    if let Some(rest) = host.strip_prefix('[') {
+        let (inner, after) = rest
+            .split_once(']')
+            .ok_or_else(|| SkipReason::InvalidHost(format!("`{}` has no closing `]`", host)))?;
+        if inner.contains('[') || inner.contains(']') {
+            return Err(SkipReason::InvalidHost(format!("`{}` has nested brackets", host)));
+        }
+        return match after {
+            "" => Ok((inner, None)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => Ok((inner, Some(port))),
+                None => Err(SkipReason::InvalidHost(format!(
+                    "unexpected `{}` after `]`",
+                    after
+                ))),
+            },
+        };
+    }
+    if host.contains('[') || host.contains(']') {
+        return Err(SkipReason::InvalidHost(format!("stray bracket in `{}`", host)));
     }
-    let len = u32::from_be_bytes(blob[0..4].try_into().ok()?) as usize;
-    if blob.len() < 4 + len {
-        return None;
+    match host.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host, Some(port))),
+        _ => Ok((host, None)),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, SkipReason> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(SkipReason::InvalidPort(port.to_string())),
     }
-    std::str::from_utf8(&blob[4..4 + len])
-        .ok()
-        .map(|s| s.to_string())
 }
 
-/// Query the local SSH agent and return identities as a vector of `SshKey`.
+/// Query the local SSH agents and return their identities as `SshKey` values.
 ///
-/// Connects to the local SSH agent, lists identities and converts them into
-/// `SshKey` values. Identities whose comments can't be parsed or whose blob
-/// doesn't contain a valid key type are skipped. Returns an `anyhow::Error` on failure.
+/// Connects to each agent in `sockets` (or the one named by `SSH_AUTH_SOCK` when
+/// `sockets` is empty), lists identities and converts them into `SshKey` values.
+/// Identities that can't be converted are returned in [`AgentKeys::skipped`].
+/// Keys held by several agents are only returned once, for the first agent
+/// listed. Returns an `anyhow::Error` wrapping an
+/// [`AgentError`](crate::agent_client::AgentError) on failure.
 ///
 /// # Examples
 ///
 /// ```no_run
 /// use ssh_agent_sync::agent::get_ssh_keys;
-/// let _keys = get_ssh_keys().unwrap();
+/// let _keys = get_ssh_keys(&[]).unwrap();
 /// ```
-pub fn get_ssh_keys() -> anyhow::Result<Vec<SshKey>> {
-    let sess = ssh2::Session::new()?;
-    let mut agent = sess.agent()?;
-    agent.connect()?;
-    agent.list_identities()?;
-    let ids = agent.identities()?;
-    let mut keys = Vec::new();
-    for id in ids {
-        let blob = id.blob();
-        let comment = id.comment();
-
-        let (user, host, name) = match process_ssh_comment(comment) {
-            Some(t) => t,
-            None => continue,
-        };
+pub fn get_ssh_keys(sockets: &[PathBuf]) -> anyhow::Result<AgentKeys> {
+    if sockets.is_empty() {
+        return get_ssh_keys_from(&LiveAgent::from_env());
+    }
+    let agents: Vec<LiveAgent> = sockets.iter().map(LiveAgent::with_socket).collect();
+    let sources: Vec<&dyn AgentSource> = agents.iter().map(|a| a as &dyn AgentSource).collect();
+    get_ssh_keys_from_all(&sources)
+}
 
-        let key_type = match get_ssh_key_type(blob) {
-            Some(k) => k,
-            None => continue,
-        };
+/// Read identities from `source` and return them as `SshKey` values.
+///
+/// Identities whose comments can't be parsed or whose blob isn't a valid
+/// public key are returned in [`AgentKeys::skipped`] with the reason.
+pub fn get_ssh_keys_from(source: &dyn AgentSource) -> anyhow::Result<AgentKeys> {
+    get_ssh_keys_from_all(&[source])
+}
+
+/// Read identities from every source and merge them into one set of keys.
+///
+/// Keys are de-duplicated by public key blob; the first source holding a key
+/// wins, and its agent socket is recorded in [`SshKey::agent`]. Fails if any
+/// source fails, so a missing agent never silently drops its keys.
+pub fn get_ssh_keys_from_all(sources: &[&dyn AgentSource]) -> anyhow::Result<AgentKeys> {
+    let mut keys = AgentKeys::default();
+    let mut seen_blobs = std::collections::HashSet::new();
+    for source in sources {
+        let agent = source.agent_socket();
+        let ids = source.identities().with_context(|| match agent {
+            Some(ref socket) => {
+                format!("Failed to read keys from agent {}", socket.path().display())
+            }
+            None => "Failed to read keys".to_string(),
+        })?;
+        for id in ids {
+            if seen_blobs.insert(id.blob.clone()) {
+                push_identity(&mut keys, &id, agent.as_ref());
+            }
+        }
+    }
+    Ok(keys)
+}
 
-        let b64 = general_purpose::STANDARD.encode(blob);
+/// Convert one identity into an `SshKey` and add it to `keys`.
+///
+/// Identities whose comment can't be parsed or whose blob isn't a valid public
+/// key are recorded as skipped instead.
+fn push_identity(keys: &mut AgentKeys, id: &AgentIdentity, agent: Option<&AgentSocket>) {
+    let b64 = general_purpose::STANDARD.encode(&id.blob);
+
+    let parsed = process_ssh_comment(&id.comment).and_then(|comment| {
+        let public_key = PublicKey::parse(&id.blob).map_err(SkipReason::BadBlob)?;
+        Ok((comment, public_key.algorithm))
+    });
 
-        keys.push(SshKey {
-            name,
-            user,
-            host,
-            key_type: key_type.clone(),
+    match parsed {
+        Ok((comment, key_type)) => {
+            // One key per target; they share a key file and are grouped into
+            // one `Host` line when the config is written.
+            for target in comment.targets {
+                keys.keys.push(SshKey {
+                    comment: id.comment.clone(),
+                    name: comment.name.clone(),
+                    user: target.user,
+                    host: target.host,
+                    port: target.port,
+                    negated_hosts: comment.negated_hosts.clone(),
+                    proxy_jump: comment.proxy_jump.clone(),
+                    options: comment.options.clone(),
+                    key_type: key_type.clone(),
+                    key: b64.clone(),
+                    file_name: String::new(),
+                    agent: agent.cloned(),
+                    certificate_file: None,
+                });
+            }
+        }
+        Err(reason) => keys.skipped.push(SkippedIdentity {
+            comment: id.comment.clone(),
             key: b64,
-            file_name: String::new(),
-        });
+            reason,
+            agent: agent.cloned(),
+        }),
     }
-    Ok(keys)
 }
 
 /// Print SSH keys to stdout in the format: "<type> <base64> <user>@<host>".
 ///
-/// Borrows the provided slice of `SshKey` and prints each key on its own line.
-pub fn print_ssh_keys(keys: &[SshKey]) {
+/// Borrows the provided slice of `SshKey` and prints each key on its own line,
+/// followed by an indented line with its fingerprint.
+pub fn print_ssh_keys(keys: &[SshKey], hash: FingerprintHash) {
     println!("ssh agent keys:");
     for key in keys {
         println!("{} {} {}@{}", key.key_type, key.key, key.user, key.host);
+        match key.public_key() {
+            Ok(public_key) => println!("    {} ({})", key.fingerprint(hash), public_key.summary()),
+            Err(_) => println!("    {}", key.fingerprint(hash)),
+        }
     }
     println!("total keys: {}", keys.len());
 }
+
+/// Print identities that were skipped, with the reason for each.
+pub fn print_skipped_identities(skipped: &[SkippedIdentity]) {
+    if skipped.is_empty() {
+        return;
+    }
+    println!("skipped identities:");
+    for identity in skipped {
+        println!("{:?}: {}", identity.comment, identity.reason);
+    }
+    println!("total skipped: {}", skipped.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ed25519_blob;
+
+    /// The key made from a single identity with `comment`, or why it was skipped.
+    fn parse(comment: &str) -> Result<SshKey, SkipReason> {
+        let agent = FakeAgent::new().with_identity(ed25519_blob(1), comment);
+        let mut keys = get_ssh_keys_from(&agent).unwrap();
+        match keys.skipped.pop() {
+            Some(skipped) => Err(skipped.reason),
+            None => Ok(keys.keys.remove(0)),
+        }
+    }
+
+    /// The `user@host[:port]` targets a comment expands to.
+    fn targets(comment: &str) -> Vec<String> {
+        let agent = FakeAgent::new().with_identity(ed25519_blob(1), comment);
+        get_ssh_keys_from(&agent)
+            .unwrap()
+            .keys
+            .iter()
+            .map(|key| match key.port {
+                Some(port) => format!("{}@{}:{}", key.user, key.host, port),
+                None => format!("{}@{}", key.user, key.host),
+            })
+            .collect()
+    }
+
+    /// A fake agent that claims to listen on `socket`, or fails to answer.
+    struct SocketAgent {
+        socket: &'static str,
+        agent: Option<FakeAgent>,
+    }
+
+    impl AgentSource for SocketAgent {
+        fn identities(&self) -> anyhow::Result<Vec<AgentIdentity>> {
+            match self.agent {
+                Some(ref agent) => agent.identities(),
+                None => anyhow::bail!("agent is locked"),
+            }
+        }
+
+        fn agent_socket(&self) -> Option<AgentSocket> {
+            Some(AgentSocket::Path(PathBuf::from(self.socket)))
+        }
+    }
+
+    #[test]
+    fn fake_agent_keys() {
+        let agent = FakeAgent::new()
+            .with_identity(ed25519_blob(1), "git@github.com")
+            .with_identity(ed25519_blob(2), "Work Laptop <me@work.example>");
+        let keys = get_ssh_keys_from(&agent).unwrap().keys;
+        assert_eq!(keys.len(), 2);
+        assert_eq!((keys[0].user.as_str(), keys[0].host.as_str()), ("git", "github.com"));
+        assert_eq!(keys[0].name, None);
+        assert_eq!(keys[0].key_type, "ssh-ed25519");
+        assert_eq!(keys[0].key, general_purpose::STANDARD.encode(ed25519_blob(1)));
+        assert_eq!(keys[1].name.as_deref(), Some("Work_Laptop"));
+        assert_eq!(keys[1].host, "work.example");
+    }
+
+    #[test]
+    fn fake_agent_skips_unusable_identities() {
+        let agent = FakeAgent::new()
+            .with_identity(ed25519_blob(1), "laptop")
+            .with_identity(ed25519_blob(2), "a@b@c")
+            .with_identity(ed25519_blob(3), "a <b@c")
+            .with_identity(ed25519_blob(4), "me @host")
+            .with_identity(ed25519_blob(5), "@host")
+            .with_identity(vec![0, 0], "git@github.com");
+        let keys = get_ssh_keys_from(&agent).unwrap();
+        assert!(keys.keys.is_empty());
+        let reasons: Vec<_> = keys.skipped.iter().map(|id| id.reason.clone()).collect();
+        assert_eq!(
+            reasons,
+            [
+                SkipReason::NoAt,
+                SkipReason::MultipleAt,
+                SkipReason::UnbalancedChevrons,
+                SkipReason::Whitespace,
+                SkipReason::EmptyUserOrHost,
+                SkipReason::BadBlob(crate::pubkey::KeyParseError::Truncated),
+            ]
+        );
+        assert_eq!(keys.skipped[0].comment, "laptop");
+        assert_eq!(keys.skipped[0].key, general_purpose::STANDARD.encode(ed25519_blob(1)));
+    }
+
+    #[test]
+    fn public_key_lines() {
+        let blob = general_purpose::STANDARD.encode(ed25519_blob(1));
+        let lines = format!("# comment\n\nssh-ed25519 {} My Box <me@box.example>\n", blob);
+        let list = PublicKeyList::from_reader(lines.as_bytes(), "test").unwrap();
+        let keys = get_ssh_keys_from(&list).unwrap().keys;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name.as_deref(), Some("My_Box"));
+        assert_eq!(keys[0].host, "box.example");
+    }
+
+    #[test]
+    fn rejects_bad_public_key_lines() {
+        let blob = general_purpose::STANDARD.encode(ed25519_blob(1));
+        for line in ["ssh-ed25519", "ssh-ed25519 !!!", &format!("ssh-rsa {} a@b", blob)] {
+            let error = PublicKeyList::from_reader(line.as_bytes(), "keys.txt").unwrap_err();
+            assert!(format!("{:#}", error).starts_with("keys.txt:1: "), "{:#}", error);
+        }
+    }
+
+    #[test]
+    fn merges_agents_first_one_wins() {
+        let first = SocketAgent {
+            socket: "/run/first.sock",
+            agent: Some(FakeAgent::new().with_identity(ed25519_blob(1), "a@one")),
+        };
+        let second = SocketAgent {
+            socket: "/run/second.sock",
+            agent: Some(
+                FakeAgent::new()
+                    .with_identity(ed25519_blob(1), "b@two")
+                    .with_identity(ed25519_blob(2), "c@three"),
+            ),
+        };
+        let keys = get_ssh_keys_from_all(&[&first, &second]).unwrap().keys;
+        let hosts: Vec<_> = keys
+            .iter()
+            .map(|key| (key.host.as_str(), key.agent.as_ref().map(|agent| agent.path())))
+            .collect();
+        assert_eq!(
+            hosts,
+            [
+                ("one", Some(Path::new("/run/first.sock"))),
+                ("three", Some(Path::new("/run/second.sock"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_agent_fails_the_read() {
+        let working = SocketAgent {
+            socket: "/run/first.sock",
+            agent: Some(FakeAgent::new().with_identity(ed25519_blob(1), "a@one")),
+        };
+        let locked = SocketAgent {
+            socket: "/run/locked.sock",
+            agent: None,
+        };
+        let error = get_ssh_keys_from_all(&[&working, &locked]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Failed to read keys from agent /run/locked.sock: agent is locked"
+        );
+    }
+
+    #[test]
+    fn ports_and_ipv6() {
+        let key = parse("ci@build:2222").unwrap();
+        assert_eq!((key.host.as_str(), key.port), ("build", Some(2222)));
+        assert_eq!(parse("ci@build port=22").unwrap().port, Some(22));
+        assert_eq!(targets("u@2001:db8::1"), ["u@2001:db8::1"]);
+        assert_eq!(targets("u@[2001:db8::1]:2222"), ["u@2001:db8::1:2222"]);
+        assert_eq!(targets("u@[build]"), ["u@build"]);
+        assert_eq!(parse("u@h:0").unwrap_err(), SkipReason::InvalidPort("0".to_string()));
+        assert_eq!(parse("u@h:ssh").unwrap_err(), SkipReason::InvalidPort("ssh".to_string()));
+        assert!(matches!(parse("u@[::1]x"), Err(SkipReason::InvalidHost(_))));
+        assert!(matches!(parse("u@[::1"), Err(SkipReason::InvalidHost(_))));
+    }
+
+    #[test]
+    fn options() {
+        let key = parse("ci@build via=admin@bastion ForwardAgent=no").unwrap();
+        assert_eq!(key.proxy_jump.as_deref(), Some("admin@bastion"));
+        assert_eq!(key.options, [("ForwardAgent".to_string(), "no".to_string())]);
+
+        let invalid = |comment| matches!(parse(comment), Err(SkipReason::InvalidOption(_)));
+        assert!(invalid("a@b ForwardAgent"));
+        assert!(invalid("a@b ForwardAgent="));
+        assert!(invalid("a@b NotAKeyword=1"));
+        assert!(invalid("a@b via=x jump=y"));
+        assert!(invalid("a@b port=1 port=2"));
+        assert!(invalid("a@b HostName=evil"));
+        assert!(invalid("a@b ProxyCommand=sh"));
+        assert!(invalid("a@b LocalCommand=sh"));
+        assert!(invalid("a@b PKCS11Provider=/tmp/lib.so"));
+    }
+
+    #[test]
+    fn rejects_multi_argument_options() {
+        assert_eq!(
+            parse("a@b LocalForward=8080").unwrap_err(),
+            SkipReason::InvalidOption("`LocalForward` needs more than one argument".to_string())
+        );
+        assert!(matches!(parse("a@b RemoteForward=9000"), Err(SkipReason::InvalidOption(_))));
+    }
+
+    fn unsafe_value(field: &str, value: &str) -> SkipReason {
+        SkipReason::UnsafeValue {
+            field: field.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_quote_in_user() {
+        assert_eq!(parse("o\"brien@host").unwrap_err(), unsafe_value("user", "o\"brien"));
+    }
+
+    #[test]
+    fn rejects_quote_in_name() {
+        assert_eq!(parse("my\"box <u@h>").unwrap_err(), unsafe_value("name", "my\"box"));
+    }
+
+    #[test]
+    fn rejects_quote_in_host() {
+        assert_eq!(parse("x@'q").unwrap_err(), unsafe_value("host", "'q"));
+    }
+
+    #[test]
+    fn rejects_comment_in_option_value() {
+        assert_eq!(parse("a@b ForwardAgent=#x").unwrap_err(), unsafe_value("ForwardAgent", "#x"));
+        assert_eq!(
+            parse("a@b ForwardAgent=\"no\"").unwrap_err(),
+            unsafe_value("ForwardAgent", "\"no\"")
+        );
+    }
+
+    #[test]
+    fn rejects_comment_in_name() {
+        assert_eq!(parse("#nick <u@h>").unwrap_err(), unsafe_value("name", "#nick"));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert_eq!(parse("u@h\u{1b}x").unwrap_err(), unsafe_value("host", "h\u{1b}x"));
+        assert_eq!(parse("u\0@h").unwrap_err(), unsafe_value("user", "u\0"));
+    }
+
+    #[test]
+    fn host_lists_and_braces() {
+        assert_eq!(targets("a@one, b@two"), ["a@one", "b@two"]);
+        assert_eq!(targets("git@{a, b}.example"), ["git@a.example", "git@b.example"]);
+        assert_eq!(targets("u@{x,y{1,2}}.z"), ["u@x.z", "u@y1.z", "u@y2.z"]);
+        assert_eq!(targets("ci@a:1, ci@b port=2"), ["ci@a:1", "ci@b:2"]);
+        // Duplicates collapse into one target.
+        assert_eq!(targets("a@one,a@one"), ["a@one"]);
+        assert!(matches!(parse("a@b,,c@d"), Err(SkipReason::InvalidTargets(_))));
+        assert!(matches!(parse("git@{a,b.example"), Err(SkipReason::InvalidTargets(_))));
+        let too_many = parse("u@{1,2}{1,2}{1,2}{1,2}{1,2}{1,2}{1,2}");
+        assert!(matches!(too_many, Err(SkipReason::InvalidTargets(_))));
+    }
+
+    #[test]
+    fn rejects_unsafe_value_in_any_target() {
+        assert_eq!(parse("a@one, b@t\"wo").unwrap_err(), unsafe_value("host", "t\"wo"));
+    }
+
+    #[test]
+    fn negated_hosts() {
+        let key = parse("ops@*.prod.example, !db.prod.example").unwrap();
+        assert_eq!(key.host, "*.prod.example");
+        assert_eq!(key.negated_hosts, ["db.prod.example"]);
+        assert_eq!(targets("ops@*.prod.example, ops@!db.prod.example"), ["ops@*.prod.example"]);
+        assert!(matches!(parse("!db.example"), Err(SkipReason::InvalidTargets(_))));
+        assert!(matches!(parse("a@b!c"), Err(SkipReason::InvalidHost(_))));
+    }
+
+    #[test]
+    fn rejects_quote_in_negated_host() {
+        assert_eq!(
+            parse("ops@*.example, !d\"b").unwrap_err(),
+            unsafe_value("negated host", "d\"b")
+        );
+        assert_eq!(parse("ops@*.example, !#db").unwrap_err(), unsafe_value("negated host", "#db"));
+    }
+}
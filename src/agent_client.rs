@@ -0,0 +1,397 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the path of the agent socket.
+pub const SSH_AUTH_SOCK_ENV: &str = "SSH_AUTH_SOCK";
+
+/// Named pipe used by the Windows OpenSSH agent when `SSH_AUTH_SOCK` is unset.
+#[cfg(windows)]
+const WINDOWS_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH2_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Largest reply we are willing to read from an agent (same limit as OpenSSH).
+const MAX_AGENT_REPLY_LEN: usize = 256 * 1024;
+
+/// Errors returned while talking to an SSH agent.
+#[derive(Debug)]
+pub enum AgentError {
+    /// No agent socket is configured, or the configured path does not exist.
+    NoSocket(Option<PathBuf>),
+    /// The socket exists but connecting to it failed, e.g. because nothing
+    /// is listening or permission was denied. The cause is the `source()`.
+    Connect(PathBuf, io::Error),
+    /// The agent answered the request with `SSH_AGENT_FAILURE`.
+    AgentFailure,
+    /// The agent sent a reply that does not follow the protocol.
+    MalformedReply(String),
+    /// Any other I/O error while reading or writing the socket.
+    Io(io::Error),
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentError::NoSocket(None) => {
+                write!(f, "no SSH agent socket: {} is not set", SSH_AUTH_SOCK_ENV)
+            }
+            AgentError::NoSocket(Some(path)) => {
+                write!(f, "no SSH agent socket at {}", path.display())
+            }
+            AgentError::Connect(path, _) => {
+                write!(f, "failed to connect to SSH agent at {}", path.display())
+            }
+            AgentError::AgentFailure => write!(f, "SSH agent returned failure"),
+            AgentError::MalformedReply(msg) => write!(f, "malformed SSH agent reply: {}", msg),
+            AgentError::Io(e) => write!(f, "SSH agent I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AgentError::Connect(_, e) | AgentError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AgentError {
+    fn from(e: io::Error) -> Self {
+        AgentError::Io(e)
+    }
+}
+
+/// A single identity as reported by the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentIdentity {
+    /// Raw public key blob in SSH wire format.
+    pub blob: Vec<u8>,
+    /// Comment attached to the key.
+    pub comment: String,
+}
+
+trait AgentStream: Read + Write {}
+
+impl<T: Read + Write> AgentStream for T {}
+
+/// Minimal client for the SSH agent protocol.
+pub struct AgentClient {
+    stream: Box<dyn AgentStream>,
+}
+
+impl AgentClient {
+    /// Connect to the agent named by `SSH_AUTH_SOCK`.
+    pub fn connect_env() -> Result<Self, AgentError> {
+        match std::env::var_os(SSH_AUTH_SOCK_ENV) {
+            Some(path) if !path.is_empty() => Self::connect(Path::new(&path)),
+            #[cfg(windows)]
+            _ => Self::connect(Path::new(WINDOWS_AGENT_PIPE)),
+            #[cfg(not(windows))]
+            _ => Err(AgentError::NoSocket(None)),
+        }
+    }
+
+    /// Connect to the agent listening on the given socket path.
+    #[cfg(unix)]
+    pub fn connect(path: &Path) -> Result<Self, AgentError> {
+        use std::os::unix::net::UnixStream;
+
+        if !path.exists() {
+            return Err(AgentError::NoSocket(Some(path.to_path_buf())));
+        }
+        let stream = UnixStream::connect(path)
+            .map_err(|e| AgentError::Connect(path.to_path_buf(), e))?;
+        Ok(Self {
+            stream: Box::new(stream),
+        })
+    }
+
+    /// Connect to the agent listening on the given named pipe.
+    #[cfg(windows)]
+    pub fn connect(path: &Path) -> Result<Self, AgentError> {
+        let pipe = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => AgentError::NoSocket(Some(path.to_path_buf())),
+                _ => AgentError::Connect(path.to_path_buf(), e),
+            })?;
+        Ok(Self {
+            stream: Box::new(pipe),
+        })
+    }
+
+    /// Ask the agent for all identities it holds (`SSH2_AGENTC_REQUEST_IDENTITIES`).
+    pub fn request_identities(&mut self) -> Result<Vec<AgentIdentity>, AgentError> {
+        let reply = self.request(&[SSH2_AGENTC_REQUEST_IDENTITIES])?;
+        let (&msg_type, mut body) = reply
+            .split_first()
+            .ok_or_else(|| AgentError::MalformedReply("empty message".to_string()))?;
+
+        match msg_type {
+            SSH2_AGENT_IDENTITIES_ANSWER => {}
+            SSH_AGENT_FAILURE => return Err(AgentError::AgentFailure),
+            other => {
+                return Err(AgentError::MalformedReply(format!(
+                    "unexpected message type {}",
+                    other
+                )));
+            }
+        }
+
+        let count = read_u32(&mut body)? as usize;
+        // Every identity needs at least two length prefixes, so a count larger
+        // than that can't be honest and would only make us over-allocate.
+        if count > body.len() / 8 {
+            return Err(AgentError::MalformedReply(format!(
+                "identity count {} exceeds reply size",
+                count
+            )));
+        }
+
+        let mut identities = Vec::with_capacity(count);
+        for _ in 0..count {
+            let blob = read_string(&mut body)?.to_vec();
+            let comment = String::from_utf8_lossy(read_string(&mut body)?).into_owned();
+            identities.push(AgentIdentity { blob, comment });
+        }
+        if !body.is_empty() {
+            return Err(AgentError::MalformedReply(format!(
+                "{} trailing bytes after identities",
+                body.len()
+            )));
+        }
+        Ok(identities)
+    }
+
+    /// Send one framed message and read the framed reply.
+    fn request(&mut self, message: &[u8]) -> Result<Vec<u8>, AgentError> {
+        let mut frame = Vec::with_capacity(4 + message.len());
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+
+        let mut len_buf = [0u8; 4];
+        self.stream.read_exact(&mut len_buf).map_err(eof_as_malformed)?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_AGENT_REPLY_LEN {
+            return Err(AgentError::MalformedReply(format!(
+                "reply length {} exceeds limit",
+                len
+            )));
+        }
+        let mut reply = vec![0u8; len];
+        self.stream.read_exact(&mut reply).map_err(eof_as_malformed)?;
+        Ok(reply)
+    }
+}
+
+/// A reply cut short by the agent closing the socket is a protocol error.
+fn eof_as_malformed(e: io::Error) -> AgentError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        AgentError::MalformedReply("connection closed mid-reply".to_string())
+    } else {
+        AgentError::Io(e)
+    }
+}
+
+fn read_u32(buf: &mut &[u8]) -> Result<u32, AgentError> {
+    if buf.len() < 4 {
+        return Err(AgentError::MalformedReply("truncated length field".to_string()));
+    }
+    let (head, rest) = buf.split_at(4);
+    *buf = rest;
+    Ok(u32::from_be_bytes([head[0], head[1], head[2], head[3]]))
+}
+
+fn read_string<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], AgentError> {
+    let len = read_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(AgentError::MalformedReply("truncated string field".to_string()));
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Connect to the agent at `socket` and list its identities.
+///
+/// When `socket` is `None` the agent named by `SSH_AUTH_SOCK` is used.
+pub fn list_agent_identities(socket: Option<&Path>) -> Result<Vec<AgentIdentity>, AgentError> {
+    let mut client = match socket {
+        Some(path) => AgentClient::connect(path)?,
+        None => AgentClient::connect_env()?,
+    };
+    client.request_identities()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::put_string;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// An in-memory agent connection that replies with fixed bytes.
+    struct FakeStream {
+        reply: io::Cursor<Vec<u8>>,
+        sent: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reply.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Frame `message` the way the agent does, with a length prefix.
+    fn framed(message: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        put_string(&mut frame, message);
+        frame
+    }
+
+    /// An identities answer claiming `count` identities, followed by `body`.
+    fn answer(count: u32, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![SSH2_AGENT_IDENTITIES_ANSWER];
+        message.extend_from_slice(&count.to_be_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// A client talking to a fake agent that answers with `reply`, and the
+    /// bytes the client sends it.
+    fn client(reply: Vec<u8>) -> (AgentClient, Rc<RefCell<Vec<u8>>>) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let stream = FakeStream {
+            reply: io::Cursor::new(reply),
+            sent: Rc::clone(&sent),
+        };
+        let client = AgentClient {
+            stream: Box::new(stream),
+        };
+        (client, sent)
+    }
+
+    fn identities_from(reply: Vec<u8>) -> Result<Vec<AgentIdentity>, AgentError> {
+        client(reply).0.request_identities()
+    }
+
+    fn malformed(result: Result<Vec<AgentIdentity>, AgentError>) -> String {
+        match result {
+            Err(AgentError::MalformedReply(msg)) => msg,
+            other => panic!("expected a malformed reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_identities() {
+        let mut body = Vec::new();
+        put_string(&mut body, b"blob one");
+        put_string(&mut body, b"git@github.com");
+        put_string(&mut body, b"blob two");
+        put_string(&mut body, b"");
+        let identities = identities_from(framed(&answer(2, &body))).unwrap();
+        assert_eq!(
+            identities,
+            [
+                AgentIdentity {
+                    blob: b"blob one".to_vec(),
+                    comment: "git@github.com".to_string(),
+                },
+                AgentIdentity {
+                    blob: b"blob two".to_vec(),
+                    comment: String::new(),
+                },
+            ]
+        );
+        assert!(identities_from(framed(&answer(0, &[]))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sends_request_identities() {
+        let (mut client, sent) = client(framed(&answer(0, &[])));
+        client.request_identities().unwrap();
+        assert_eq!(*sent.borrow(), [0, 0, 0, 1, SSH2_AGENTC_REQUEST_IDENTITIES]);
+    }
+
+    #[test]
+    fn agent_failure() {
+        let result = identities_from(framed(&[SSH_AGENT_FAILURE]));
+        assert!(matches!(result, Err(AgentError::AgentFailure)));
+    }
+
+    #[test]
+    fn rejects_unexpected_or_empty_messages() {
+        assert!(malformed(identities_from(framed(&[99]))).contains("unexpected message type 99"));
+        assert!(malformed(identities_from(framed(&[]))).contains("empty"));
+    }
+
+    #[test]
+    fn rejects_truncated_replies() {
+        // The frame promises more bytes than the agent sends before closing.
+        let mut reply = framed(&answer(0, &[]));
+        reply[3] += 10;
+        assert!(malformed(identities_from(reply)).contains("closed"));
+        assert!(malformed(identities_from(vec![0, 0])).contains("closed"));
+
+        // The frame is complete, but an identity inside it is cut short.
+        let mut body = Vec::new();
+        put_string(&mut body, b"blob");
+        body.extend_from_slice(&[0, 0, 0, 9, b'x']);
+        assert!(malformed(identities_from(framed(&answer(1, &body)))).contains("truncated"));
+    }
+
+    #[test]
+    fn rejects_count_larger_than_reply() {
+        let msg = malformed(identities_from(framed(&answer(1000, &[0; 16]))));
+        assert!(msg.contains("identity count 1000"), "{}", msg);
+        let msg = malformed(identities_from(framed(&answer(u32::MAX, &[]))));
+        assert!(msg.contains("exceeds reply size"), "{}", msg);
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut body = Vec::new();
+        put_string(&mut body, b"blob");
+        put_string(&mut body, b"comment");
+        body.extend_from_slice(&[1, 2, 3]);
+        let msg = malformed(identities_from(framed(&answer(1, &body))));
+        assert_eq!(msg, "3 trailing bytes after identities");
+    }
+
+    #[test]
+    fn rejects_oversized_reply() {
+        let len = (MAX_AGENT_REPLY_LEN + 1) as u32;
+        let msg = malformed(identities_from(len.to_be_bytes().to_vec()));
+        assert!(msg.contains("exceeds limit"), "{}", msg);
+    }
+
+    #[test]
+    fn connect_error_names_cause_once() {
+        use std::error::Error as _;
+        let error = AgentError::Connect(
+            PathBuf::from("/run/agent.sock"),
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        assert_eq!(error.to_string(), "failed to connect to SSH agent at /run/agent.sock");
+        assert!(error.source().is_some());
+    }
+}
@@ -9,19 +9,17 @@ use std::thread;
 use std::time::Duration;
 use tray_icon::{
     TrayIconBuilder,
-    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem},
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, Submenu},
 };
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 
-use ssh_agent_sync::add_keys_to_config;
 use ssh_agent_sync::constants;
-use ssh_agent_sync::get_ssh_keys;
+use ssh_agent_sync::{apply_sync_plan, plan_sync};
+use ssh_agent_sync::{Settings, SyncOptions, get_ssh_keys};
 
 use rust_embed::Embed;
-#[cfg(target_os = "linux")]
-use gtk;
 
 #[derive(Embed)]
 #[folder = "assets/"]
@@ -55,6 +53,10 @@ impl Drop for SyncGuard {
 /// UI commands sent from background threads to the UI thread
 enum UiCommand {
     PerformingSync(bool),
+    /// Number of keys read and a description of each skipped identity.
+    KeysRead(usize, Vec<String>),
+    /// Something the user should look at after a sync, or `None` if all went well.
+    Warning(Option<String>),
 }
 
 #[allow(dead_code)]
@@ -84,6 +86,60 @@ fn load_icon_embedded(name: &str) -> tray_icon::Icon {
     tray_icon::Icon::from_rgba(rgba, width, height).expect("Failed to create tray icon")
 }
 
+/// Read the agent keys and sync them, returning a warning for the tray if
+/// anything needs attention.
+fn sync_once(ui_tx: Option<&Sender<UiCommand>>) -> Option<String> {
+    // Re-read settings on every sync so edits apply without restarting the tray app.
+    // Syncing with defaults would drop the key policy, so skip the sync instead.
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => return Some(format!("Failed to load settings, skipping sync: {}", e)),
+    };
+    let options = SyncOptions {
+        identity_agent: settings.identity_agent,
+        fingerprint_hash: settings.fingerprint_hash,
+        host_template: settings.host_template.clone(),
+        global_defaults: settings.global_defaults.clone(),
+        host_conflict: settings.host_conflict,
+        key_file_naming: settings.key_file_naming,
+        ..SyncOptions::default()
+    };
+
+    // Syncing an empty key list would wipe the config, so leave it alone
+    // while the agent can't be read, e.g. while a vault is locked.
+    let mut keys = match get_ssh_keys(&settings.agent_sockets) {
+        Ok(keys) => keys,
+        Err(e) => return Some(format!("Failed to read agent keys: {:#}", e)),
+    };
+    let violations = settings.policy.apply(&mut keys.keys);
+    if let Some(tx) = ui_tx {
+        let skipped = keys
+            .skipped
+            .iter()
+            .map(|id| format!("{}: {}", id.comment, id.reason))
+            .chain(
+                violations
+                    .iter()
+                    .filter(|violation| violation.excluded)
+                    .map(|violation| format!("{}: {}", violation.key.comment, violation.reason)),
+            )
+            .collect();
+        let _ = tx.send(UiCommand::KeysRead(keys.keys.len(), skipped));
+    }
+
+    let plan = match plan_sync(&mut keys.keys, &options) {
+        Ok(plan) => plan,
+        Err(e) => return Some(format!("Failed to add keys to config: {}", e)),
+    };
+    if let Err(e) = apply_sync_plan(&plan) {
+        return Some(format!("Failed to add keys to config: {}", e));
+    }
+    if plan.modified_on_disk {
+        return Some("Generated SSH config was edited on disk and has been restored".to_string());
+    }
+    None
+}
+
 fn sync_ssh(in_progress: &Arc<AtomicBool>, ui_tx: Option<&Sender<UiCommand>>) {
     if let Some(_guard) = SyncGuard::try_acquire(in_progress) {
         // notify UI to disable "Check Now" while running
@@ -91,9 +147,12 @@ fn sync_ssh(in_progress: &Arc<AtomicBool>, ui_tx: Option<&Sender<UiCommand>>) {
             let _ = tx.send(UiCommand::PerformingSync(true));
         }
 
-        let mut keys = get_ssh_keys().unwrap_or_default();
-        if let Err(e) = add_keys_to_config(&mut keys, false) {
-            eprintln!("Failed to add keys to config: {}", e);
+        let warning = sync_once(ui_tx);
+        if let Some(ref warning) = warning {
+            eprintln!("{}", warning);
+        }
+        if let Some(tx) = ui_tx {
+            let _ = tx.send(UiCommand::Warning(warning));
         }
 
         // notify UI to re-enable it after completion
@@ -125,6 +184,12 @@ fn main() {
     let tray_menu = Menu::new();
     let check_now = MenuItem::new("Check Now", true, None);
 
+    // Shows the last sync problem, if any
+    let status_item = MenuItem::new("Config Up to Date", false, None);
+
+    // Lists identities that could not be synced; filled in after each sync
+    let skipped_menu = Submenu::new("No Skipped Keys", false);
+
     // Toggle for the task itself
     let task_enabled = CheckMenuItem::new("Monitoring Enabled", true, true, None);
 
@@ -135,7 +200,7 @@ fn main() {
     let quit_item = MenuItem::new("Quit", true, None);
 
     tray_menu
-        .append_items(&[&check_now, &task_enabled, &boot_enabled, &quit_item])
+        .append_items(&[&status_item, &check_now, &skipped_menu, &task_enabled, &boot_enabled, &quit_item])
         .unwrap();
 
     // Channel for UI commands (e.g., enable/disable menu items)
@@ -166,7 +231,7 @@ fn main() {
     }
 
     let icon = load_icon_embedded("icon.png");
-    let _tray_icon = TrayIconBuilder::new()
+    let tray_icon = TrayIconBuilder::new()
         .with_menu(Box::new(tray_menu))
         .with_tooltip(crate::constants::PROGRAM_NAME)
         .with_icon(icon)
@@ -177,6 +242,9 @@ fn main() {
         menu_channel: tray_icon::menu::MenuEventReceiver,
         quit_item: MenuItem,
         check_now: MenuItem,
+        status_item: MenuItem,
+        skipped_menu: Submenu,
+        tray_icon: tray_icon::TrayIcon,
         task_enabled: CheckMenuItem,
         boot_enabled: CheckMenuItem,
         is_running: Arc<AtomicBool>,
@@ -184,6 +252,57 @@ fn main() {
         ui_cmd_tx: Sender<UiCommand>,
         ui_cmd_rx: Receiver<UiCommand>,
         auto_gui: auto_launch::AutoLaunch,
+        /// Keys synced and skipped by the last sync, for the tooltip.
+        synced: usize,
+        skipped: usize,
+        warning: Option<String>,
+    }
+
+    impl App {
+        /// Refresh the skipped keys submenu after keys were read.
+        fn show_skipped(&mut self, synced: usize, skipped: &[String]) {
+            while self.skipped_menu.remove_at(0).is_some() {}
+            for description in skipped {
+                let _ = self
+                    .skipped_menu
+                    .append(&MenuItem::new(description, false, None));
+            }
+
+            if skipped.is_empty() {
+                self.skipped_menu.set_text("No Skipped Keys");
+            } else {
+                self.skipped_menu
+                    .set_text(format!("Skipped Keys ({})", skipped.len()));
+            }
+            self.skipped_menu.set_enabled(!skipped.is_empty());
+
+            self.synced = synced;
+            self.skipped = skipped.len();
+            self.update_tooltip();
+        }
+
+        /// Show or clear the warning from the last sync.
+        fn show_warning(&mut self, warning: Option<String>) {
+            match warning {
+                Some(ref warning) => self.status_item.set_text(format!("⚠ {}", warning)),
+                None => self.status_item.set_text("Config Up to Date"),
+            }
+            self.warning = warning;
+            self.update_tooltip();
+        }
+
+        fn update_tooltip(&self) {
+            let mut tooltip = format!(
+                "{}: {} keys, {} skipped",
+                crate::constants::PROGRAM_NAME,
+                self.synced,
+                self.skipped
+            );
+            if let Some(ref warning) = self.warning {
+                tooltip = format!("⚠ {}\n{}", tooltip, warning);
+            }
+            let _ = self.tray_icon.set_tooltip(Some(tooltip));
+        }
     }
 
     impl ApplicationHandler for App {
@@ -205,7 +324,13 @@ fn main() {
             while let Ok(cmd) = self.ui_cmd_rx.try_recv() {
                 match cmd {
                     UiCommand::PerformingSync(enabled) => {
-                        let _ = self.check_now.set_enabled(!enabled);
+                        self.check_now.set_enabled(!enabled);
+                    }
+                    UiCommand::KeysRead(synced, skipped) => {
+                        self.show_skipped(synced, &skipped);
+                    }
+                    UiCommand::Warning(warning) => {
+                        self.show_warning(warning);
                     }
                 }
             }
@@ -235,6 +360,9 @@ fn main() {
         menu_channel: menu_channel.clone(),
         quit_item,
         check_now,
+        status_item,
+        skipped_menu,
+        tray_icon,
         task_enabled,
         boot_enabled,
         is_running: Arc::clone(&is_running),
@@ -242,6 +370,9 @@ fn main() {
         ui_cmd_tx: ui_cmd_tx.clone(),
         ui_cmd_rx,
         auto_gui,
+        synced: 0,
+        skipped: 0,
+        warning: None,
     };
 
     let _ = event_loop.run_app(&mut app);
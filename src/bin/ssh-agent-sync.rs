@@ -1,9 +1,11 @@
 use clap::Parser;
 use colored::*;
-use ssh_agent_sync::print_ssh_keys;
-use ssh_agent_sync::get_ssh_keys;
-use ssh_agent_sync::add_keys_to_config;
+use ssh_agent_sync::{print_policy_violations, print_skipped_identities, print_ssh_keys};
+use ssh_agent_sync::{AgentSource, LiveAgent, PublicKeyList, get_ssh_keys_from_all};
+use ssh_agent_sync::{SyncPlan, add_keys_to_config, apply_sync_plan, plan_sync, print_sync_plan, verify_sync};
 use ssh_agent_sync::constants;
+use ssh_agent_sync::{FingerprintHash, GlobalDefaults, HostConflictPolicy, IdentityAgentMode, KeyFileNaming, PolicyAction, Settings, SyncOptions};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about = crate::constants::PROGRAM_NAME, arg_required_else_help = true)]
@@ -13,26 +15,252 @@ struct Args {
     print: bool,
     /// sync ssh agent keys to ssh config
     #[arg(long)]
-    sync: bool, 
-    // Force sync even if CRC matches (not implemented yet)
+    sync: bool,
+    /// Sync even if nothing changed since the last sync, rewriting every generated file
     #[arg(long)]
     force: bool,
+    /// Show what --sync would change without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Check the generated config and key files against the agent keys; exits 1 if they differ
+    #[arg(long)]
+    verify: bool,
+    /// Rewrite generated files that were edited or deleted, like --verify followed by a sync
+    #[arg(long)]
+    repair: bool,
+    /// Read public keys from files (or `-` for `ssh-add -L` output on stdin) instead of the agent
+    #[arg(long, value_name = "PATH")]
+    keys_from: Vec<String>,
+    /// Read keys from the agent listening on this socket instead of `SSH_AUTH_SOCK` (repeatable)
+    #[arg(long, value_name = "PATH")]
+    agent_socket: Vec<PathBuf>,
+    /// Pin each generated host to the agent its key was read from: off, auth-sock or path
+    #[arg(long, value_name = "MODE")]
+    identity_agent: Option<IdentityAgentMode>,
+    /// Hash used for key fingerprints: sha256 or md5
+    #[arg(long, value_name = "HASH")]
+    fingerprint_hash: Option<FingerprintHash>,
+    /// Template file for the directives in each host block
+    #[arg(long, value_name = "PATH")]
+    host_template: Option<PathBuf>,
+    /// `Host *` block after the host blocks of the generated config: on, off, or a file of directives
+    #[arg(long, value_name = "on|off|PATH")]
+    global_defaults: Option<GlobalDefaults>,
+    /// What to do when two keys claim the same host: first or error
+    #[arg(long, value_name = "POLICY")]
+    host_conflict: Option<HostConflictPolicy>,
+    /// Name key files after the key's nickname or host (name) or its fingerprint
+    #[arg(long, value_name = "NAMING")]
+    key_file_naming: Option<KeyFileNaming>,
+    /// Reject RSA keys smaller than this many bits
+    #[arg(long, value_name = "BITS")]
+    min_rsa_bits: Option<u32>,
+    /// Only sync keys of this algorithm, e.g. ssh-ed25519 (repeatable)
+    #[arg(long, value_name = "ALGORITHM")]
+    allow_algorithm: Vec<String>,
+    /// Reject keys whose comment matches this wildcard pattern (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    deny_comment: Vec<String>,
+    /// What to do with keys that break the policy: exclude or warn
+    #[arg(long, value_name = "ACTION")]
+    policy_action: Option<PolicyAction>,
+    /// Save the given options as defaults for future runs and the tray app
+    #[arg(long)]
+    save_settings: bool,
+}
+
+impl Args {
+    /// Whether any flag asks for keys to be read, as opposed to only saving settings.
+    fn has_action(&self) -> bool {
+        self.print || self.sync || self.dry_run || self.verify || self.repair
+    }
+}
+
+/// Describe the outcome of `--verify` or `--repair`.
+fn print_verification(plan: &SyncPlan) {
+    if plan.is_empty() {
+        println!("{}", "Generated files match the agent keys".green());
+        return;
+    }
+    print_sync_plan(plan);
+    let message = if plan.modified_on_disk {
+        "Generated files were edited or deleted since the last sync"
+    } else {
+        "Generated files are out of date with the agent keys"
+    };
+    eprintln!("{}", message.yellow());
+}
+
+/// Build the key sources selected on the command line or in the settings.
+fn key_sources(args: &Args, settings: &Settings) -> anyhow::Result<Vec<Box<dyn AgentSource>>> {
+    if args.keys_from.is_empty() {
+        if settings.agent_sockets.is_empty() {
+            return Ok(vec![Box::new(LiveAgent::from_env())]);
+        }
+        return Ok(settings
+            .agent_sockets
+            .iter()
+            .map(|socket| Box::new(LiveAgent::with_socket(socket)) as Box<dyn AgentSource>)
+            .collect());
+    }
+
+    let mut files = Vec::new();
+    let mut read_stdin = false;
+    for path in &args.keys_from {
+        if path == "-" {
+            read_stdin = true;
+        } else {
+            files.push(path.as_str());
+        }
+    }
+
+    let mut list = PublicKeyList::from_files(&files)?;
+    if read_stdin {
+        let stdin = PublicKeyList::from_reader(std::io::stdin().lock(), "<stdin>")?;
+        list.extend(stdin);
+    }
+    Ok(vec![Box::new(list)])
 }
 
 fn main() {
     let args = Args::parse();
-    let mut keys = get_ssh_keys().unwrap_or_default();
+    let mut settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("Failed to load settings: {}", e);
+        std::process::exit(1);
+    });
+    if !args.agent_socket.is_empty() {
+        // Saved settings are also read from other directories, e.g. by the tray app.
+        settings.agent_sockets = args
+            .agent_socket
+            .iter()
+            .map(|socket| std::path::absolute(socket).unwrap_or_else(|_| socket.clone()))
+            .collect();
+    }
+    if let Some(mode) = args.identity_agent {
+        settings.identity_agent = mode;
+    }
+    if let Some(hash) = args.fingerprint_hash {
+        settings.fingerprint_hash = hash;
+    }
+    if let Some(ref path) = args.host_template {
+        settings.host_template = Some(std::path::absolute(path).unwrap_or_else(|_| path.clone()));
+    }
+    if let Some(ref defaults) = args.global_defaults {
+        settings.global_defaults = match defaults {
+            GlobalDefaults::Custom(path) => {
+                GlobalDefaults::Custom(std::path::absolute(path).unwrap_or_else(|_| path.clone()))
+            }
+            other => other.clone(),
+        };
+    }
+    if let Some(policy) = args.host_conflict {
+        settings.host_conflict = policy;
+    }
+    if let Some(naming) = args.key_file_naming {
+        settings.key_file_naming = naming;
+    }
+    if let Some(bits) = args.min_rsa_bits {
+        settings.policy.min_rsa_bits = Some(bits);
+    }
+    if !args.allow_algorithm.is_empty() {
+        settings.policy.allowed_algorithms = args.allow_algorithm.clone();
+    }
+    if !args.deny_comment.is_empty() {
+        settings.policy.denied_comments = args.deny_comment.clone();
+    }
+    if let Some(action) = args.policy_action {
+        settings.policy.action = action;
+    }
+    if args.save_settings {
+        if let Err(e) = settings.save() {
+            eprintln!("Failed to save settings: {}", e);
+            std::process::exit(1);
+        }
+        if !args.has_action() {
+            println!("Settings saved.");
+            std::process::exit(0);
+        }
+    }
+
+    let mut keys = key_sources(&args, &settings)
+        .and_then(|sources| {
+            let sources: Vec<&dyn AgentSource> = sources.iter().map(|s| s.as_ref()).collect();
+            get_ssh_keys_from_all(&sources)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read ssh keys: {:#}", e);
+            std::process::exit(1);
+        });
 
     println!("{}", "───────────────────────".bright_black());
     println!(" {} {}", crate::constants::PROGRAM_NAME.bold().blue(), format!("v{}", crate::constants::PROGRAM_VERSION).dimmed());
     println!("{}", "───────────────────────".bright_black());
 
+    let violations = settings.policy.apply(&mut keys.keys);
+
     if args.print {
-        print_ssh_keys(&keys);
+        print_ssh_keys(&keys.keys, settings.fingerprint_hash);
+        print_skipped_identities(&keys.skipped);
+        print_policy_violations(&violations);
+    } else {
+        if !keys.skipped.is_empty() {
+            eprintln!(
+                "{}",
+                format!(
+                    "Skipped {} identities with unusable comments or keys, use --print for details",
+                    keys.skipped.len()
+                )
+                .yellow()
+            );
+        }
+        for violation in &violations {
+            let action = if violation.excluded { "Excluded" } else { "Warning" };
+            eprintln!(
+                "{}",
+                format!("{} {:?}: {}", action, violation.key.comment, violation.reason).yellow()
+            );
+        }
     }
 
-    if args.sync {
-        if let Err(e) = add_keys_to_config(&mut keys, args.force) {
+    if args.sync || args.dry_run || args.verify || args.repair {
+        let options = SyncOptions {
+            force: args.force,
+            identity_agent: settings.identity_agent,
+            fingerprint_hash: settings.fingerprint_hash,
+            host_template: settings.host_template.clone(),
+            global_defaults: settings.global_defaults.clone(),
+            host_conflict: settings.host_conflict,
+            key_file_naming: settings.key_file_naming,
+        };
+        if args.dry_run {
+            match plan_sync(&mut keys.keys, &options) {
+                Ok(plan) => print_sync_plan(&plan),
+                Err(e) => {
+                    eprintln!("Failed to plan sync: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        }
+        if args.verify || args.repair {
+            let plan = verify_sync(&mut keys.keys, &options).unwrap_or_else(|e| {
+                eprintln!("Failed to verify generated files: {}", e);
+                std::process::exit(1);
+            });
+            print_verification(&plan);
+            if args.verify {
+                std::process::exit(if plan.is_empty() { 0 } else { 1 });
+            }
+            if let Err(e) = apply_sync_plan(&plan) {
+                eprintln!("Failed to repair generated files: {}", e);
+                std::process::exit(1);
+            }
+            if !plan.is_empty() {
+                println!("Repaired {} files.", plan.changes.len());
+            }
+            std::process::exit(0);
+        }
+        if let Err(e) = add_keys_to_config(&mut keys.keys, &options) {
             eprintln!("Failed to add keys to config: {}", e);
             std::process::exit(1);
         }
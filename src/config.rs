@@ -1,172 +1,817 @@
-use crate::types::SshKey;
+use crate::keywords::is_ssh_config_keyword;
+use crate::overrides::Overrides;
+use crate::ssh_config::{SshConfig, parse_line};
+use crate::pattern::{is_host_pattern, path_glob_match, pattern_specificity};
+use crate::template::HostTemplate;
+use crate::types::{AgentSocket, FingerprintHash, SshKey};
 use base64::engine::Engine;
 use sha2::{Digest, Sha256};
 extern crate sanitize_filename;
 
-fn get_current_ssh_keys_crc() -> Option<String> {
-    use std::fs;
+/// How generated host blocks are pinned to the agent their key was read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdentityAgentMode {
+    /// Don't write `IdentityAgent`; ssh uses whatever `SSH_AUTH_SOCK` is set.
+    #[default]
+    Off,
+    /// Write `IdentityAgent SSH_AUTH_SOCK` for keys read through `SSH_AUTH_SOCK`,
+    /// and the literal socket path for keys read from an explicit socket.
+    AuthSock,
+    /// Always write the literal socket path the key was read from.
+    Path,
+}
 
-    let config_path = crate::constants::ssh_config_file_path()?;
-    let content = fs::read_to_string(&config_path).ok()?;
+impl std::str::FromStr for IdentityAgentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "no" => Ok(IdentityAgentMode::Off),
+            "auth-sock" | "ssh_auth_sock" => Ok(IdentityAgentMode::AuthSock),
+            "path" | "yes" => Ok(IdentityAgentMode::Path),
+            _ => Err(format!("expected off, auth-sock or path, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for IdentityAgentMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IdentityAgentMode::Off => "off",
+            IdentityAgentMode::AuthSock => "auth-sock",
+            IdentityAgentMode::Path => "path",
+        })
+    }
+}
+
+/// The `Host *` block written after the host blocks of the generated config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum GlobalDefaults {
+    /// `Host *` with `IdentitiesOnly yes`, so hosts that aren't synced only
+    /// get keys from their own config or `-i`.
+    #[default]
+    On,
+    /// No `Host *` block; unsynced hosts may be offered any agent key.
+    Off,
+    /// `Host *` with the directives in this file, one `Keyword value` per line.
+    Custom(std::path::PathBuf),
+}
+
+impl std::str::FromStr for GlobalDefaults {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "on" | "yes" => Ok(GlobalDefaults::On),
+            "off" | "no" => Ok(GlobalDefaults::Off),
+            "" => Err("expected on, off or a file path".to_string()),
+            _ => Ok(GlobalDefaults::Custom(crate::settings::expand_tilde(s))),
+        }
+    }
+}
+
+impl std::fmt::Display for GlobalDefaults {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GlobalDefaults::On => f.write_str("on"),
+            GlobalDefaults::Off => f.write_str("off"),
+            GlobalDefaults::Custom(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl GlobalDefaults {
+    /// The directives of the `Host *` block, empty when it is turned off.
+    pub fn directives(&self) -> Result<Vec<(String, String)>, String> {
+        let path = match self {
+            GlobalDefaults::On => return Ok(vec![("IdentitiesOnly".into(), "yes".into())]),
+            GlobalDefaults::Off => return Ok(Vec::new()),
+            GlobalDefaults::Custom(path) => path,
+        };
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read global defaults {}: {}", path.display(), e)
+        })?;
+        let mut directives = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let error = |msg: String| format!("{}: line {}: {}", path.display(), idx + 1, msg);
+            let Some(line) = parse_line(idx + 1, line).map_err(error)? else {
+                continue;
+            };
+            if !is_ssh_config_keyword(&line.keyword) {
+                return Err(error(format!("unknown ssh_config keyword `{}`", line.keyword)));
+            }
+            if line.is("host") || line.is("match") || line.is("include") {
+                return Err(error(format!("`{}` can't be used in the global defaults", line.keyword)));
+            }
+            directives.push((line.keyword, line.value));
+        }
+        Ok(directives)
+    }
+}
+
+/// How key files in the key folder are named.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyFileNaming {
+    /// After the key's nickname, or its host if it has none, e.g. `github_com.pub`.
+    #[default]
+    Name,
+    /// After the key's SHA256 fingerprint, e.g. `SHA256_ydemjxdY....pub`, so
+    /// paths don't change when a key is renamed. The file's comment holds
+    /// the key's agent comment.
+    Fingerprint,
+}
+
+impl std::str::FromStr for KeyFileNaming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "name" => Ok(KeyFileNaming::Name),
+            "fingerprint" => Ok(KeyFileNaming::Fingerprint),
+            _ => Err(format!("expected name or fingerprint, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyFileNaming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyFileNaming::Name => "name",
+            KeyFileNaming::Fingerprint => "fingerprint",
+        })
+    }
+}
+
+/// What to do when several keys produce `Host` blocks for the same host or
+/// nickname. ssh only uses the first block's `User`, so the later ones would
+/// silently do the wrong thing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostConflictPolicy {
+    /// Keep the first key's block, drop the host from the others and warn.
+    #[default]
+    First,
+    /// Refuse to sync until the conflict is resolved.
+    Error,
+}
+
+impl std::str::FromStr for HostConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "first" => Ok(HostConflictPolicy::First),
+            "error" => Ok(HostConflictPolicy::Error),
+            _ => Err(format!("expected first or error, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for HostConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HostConflictPolicy::First => "first",
+            HostConflictPolicy::Error => "error",
+        })
+    }
+}
+
+/// Options controlling how keys are written to the ssh config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Sync even if the stored CRC matches the current keys.
+    pub force: bool,
+    /// Whether and how to emit `IdentityAgent` in each host block.
+    pub identity_agent: IdentityAgentMode,
+    /// Hash used for the fingerprint comment above each host block.
+    pub fingerprint_hash: FingerprintHash,
+    /// Template for the directives in each host block; `None` uses the built-in one.
+    pub host_template: Option<std::path::PathBuf>,
+    /// The `Host *` block after the host blocks of the generated config.
+    pub global_defaults: GlobalDefaults,
+    /// What to do when two keys produce a `Host` block for the same host.
+    pub host_conflict: HostConflictPolicy,
+    /// How key files are named.
+    pub key_file_naming: KeyFileNaming,
+}
+
+/// Version of the serialization hashed by [`generate_sync_digest`]. Bump it
+/// whenever the generated output changes for the same inputs.
+const SYNC_DIGEST_VERSION: u64 = 3;
+
+/// The value of the header line starting with `prefix` in the generated config.
+fn read_config_header(content: &str, prefix: &str) -> Option<String> {
+    content
+        .lines()
+        .take_while(|line| line.starts_with("### "))
+        .find_map(|line| line.strip_prefix(prefix))
+        .map(|value| value.trim().to_string())
+}
+
+/// SHA256 over a sequence of length-prefixed fields, so that no two
+/// different sequences hash the same bytes.
+struct DigestWriter(Sha256);
 
-    for line in content.lines() {
-        if line.starts_with(crate::constants::SSH_AGENT_SYNC_CRC_PREFIX) {
-            let crc = line
-                .trim_start_matches(crate::constants::SSH_AGENT_SYNC_CRC_PREFIX)
-                .trim();
-            return Some(crc.to_string());
+impl DigestWriter {
+    fn new() -> Self {
+        let mut writer = Self(Sha256::new());
+        writer.count(SYNC_DIGEST_VERSION as usize);
+        writer.field(crate::constants::PROGRAM_VERSION);
+        writer
+    }
+
+    fn count(&mut self, count: usize) {
+        self.0.update((count as u64).to_le_bytes());
+    }
+
+    fn field(&mut self, value: impl AsRef<[u8]>) {
+        let value = value.as_ref();
+        self.count(value.len());
+        self.0.update(value);
+    }
+
+    fn optional(&mut self, value: Option<impl AsRef<[u8]>>) {
+        match value {
+            Some(value) => {
+                self.count(1);
+                self.field(value);
+            }
+            None => self.count(0),
         }
     }
-    None
+
+    fn finish(self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.0.finalize())
+    }
 }
 
-fn generate_ssh_keys_crc(keys: &Vec<SshKey>) -> String {
-    let mut hasher = Sha256::new();
+/// Digest of everything that goes into the generated output: the keys as
+/// parsed from their comments, the sync options, global defaults, template
+/// and overrides, and the tool version.
+fn generate_sync_digest(
+    keys: &[SshKey],
+    options: &SyncOptions,
+    overrides: &Overrides,
+    template: &HostTemplate,
+    global_block: &str,
+) -> String {
+    let mut digest = DigestWriter::new();
+    digest.field(options.identity_agent.to_string());
+    digest.field(options.fingerprint_hash.to_string());
+    digest.field(options.host_conflict.to_string());
+    digest.field(options.key_file_naming.to_string());
+    digest.field(global_block);
+
+    digest.count(keys.len());
     for key in keys {
-        hasher.update(key.user.as_bytes());
-        hasher.update(key.host.as_bytes());
-        hasher.update(key.key.as_bytes());
+        digest.field(&key.comment);
+        digest.optional(key.name.as_ref());
+        digest.field(&key.user);
+        digest.field(&key.host);
+        digest.optional(key.port.map(|port| port.to_string()));
+        digest.count(key.negated_hosts.len());
+        for host in &key.negated_hosts {
+            digest.field(host);
+        }
+        digest.optional(key.proxy_jump.as_ref());
+        digest.count(key.options.len());
+        for (keyword, value) in &key.options {
+            digest.field(keyword);
+            digest.field(value);
+        }
+        digest.field(&key.key_type);
+        digest.field(&key.key);
+        // Only the agent that ends up in the config matters, not where the key was read from.
+        digest.optional(identity_agent_value(key, options.identity_agent));
+    }
+
+    digest.count(template.lines().len());
+    for line in template.lines() {
+        digest.field(line);
     }
-    let result = hasher.finalize();
-    base64::engine::general_purpose::STANDARD.encode(result)
+
+    digest.count(overrides.sections.len());
+    for section in &overrides.sections {
+        digest.count(section.hosts.len());
+        for host in &section.hosts {
+            digest.field(host);
+        }
+        digest.count(section.directives.len());
+        for directive in &section.directives {
+            digest.field(&directive.keyword);
+            digest.field(&directive.value);
+        }
+    }
+    digest.finish()
 }
 
-/// Create a file for the given SSH key in the specified path.
-///
-/// The file name is derived from the key's name or host, sanitized for filesystem use.
+/// Digest of the files a sync writes: the generated config below its header
+/// and each key file, by name. Compared against the files on disk to notice
+/// when they were edited or deleted since the last sync.
+fn generate_output_digest(config_body: &str, key_files: &[(String, String)]) -> String {
+    let mut digest = DigestWriter::new();
+    digest.field(config_body);
+    digest.count(key_files.len());
+    for (file_name, content) in key_files {
+        digest.field(file_name);
+        digest.field(content);
+    }
+    digest.finish()
+}
+
+/// The output digest of the generated config and the key files in the
+/// manifest as they are on disk, or `None` if any of them can't be read.
+fn current_output_digest(config: &str, key_folder: &std::path::Path) -> Option<String> {
+    let (_, body) = config.split_once("\n\n")?;
+    // A sync without keys writes no manifest, which is the same as an empty one.
+    let manifest = read_existing(&crate::constants::ssh_key_manifest_file_path()?).ok()?;
+    let manifest = manifest.unwrap_or_default();
+    let mut key_files = Vec::new();
+    for file_name in parse_manifest(&manifest) {
+        let content = std::fs::read_to_string(key_folder.join(&file_name)).ok()?;
+        key_files.push((file_name, content));
+    }
+    Some(generate_output_digest(body, &key_files))
+}
+
+/// Whether the last sync's output is still in place.
 ///
-/// The file will contain the SSH public key in the format: "<type> <base64> <user>@<host>".
+/// Returns `None` if the generated config wasn't made from the inputs with
+/// `digest`. Otherwise returns whether the config, every key file and the
+/// `Include` in `~/.ssh/config` are still the way that sync left them.
+fn last_sync_intact(digest: &str, key_folder: &std::path::Path) -> Option<bool> {
+    let config_path = crate::constants::ssh_config_file_path()?;
+    let config = std::fs::read_to_string(&config_path).ok()?;
+    let stored_digest = read_config_header(&config, crate::constants::SSH_AGENT_SYNC_CRC_PREFIX);
+    if stored_digest.as_deref() != Some(digest) {
+        return None;
+    }
+    let stored_output = read_config_header(&config, crate::constants::SSH_AGENT_SYNC_OUTPUT_PREFIX);
+    Some(
+        stored_output.is_some()
+            && stored_output == current_output_digest(&config, key_folder)
+            && check_base_config_needs_editing() == Ok(false),
+    )
+}
+
+/// The key file name for the given SSH key.
 ///
-/// Returns `Ok(())` on success, or an error message as `Err(String)` on failure.
-fn create_key_file(key: &mut SshKey, path: &std::path::Path) -> Result<(), String> {
-    key.file_name = match &key.name {
+/// With [`KeyFileNaming::Name`] the file name is derived from the key's name or
+/// host, with `.`, `:`, `%` and spaces turned into `_`, and sanitized for
+/// filesystem use. Keys whose names collide get a short fingerprint suffix,
+/// see [`key_file_names`].
+/// With [`KeyFileNaming::Fingerprint`] it is the URL-safe base64 SHA256 fingerprint.
+/// Certificates get a `-cert` suffix, following the OpenSSH naming convention.
+fn key_file_name(key: &SshKey, naming: KeyFileNaming, with_fingerprint: bool) -> String {
+    let mut file_name = match naming {
+        KeyFileNaming::Name => key_file_stem(key, with_fingerprint),
+        KeyFileNaming::Fingerprint => format!(
+            "SHA256_{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(key.blob()))
+        ),
+    };
+    if key.is_certificate() {
+        file_name.push_str("-cert");
+    }
+    file_name.push_str(".pub");
+    file_name
+}
+
+/// Key file name from the key's name or host, before any suffixes.
+fn key_file_stem(key: &SshKey, with_fingerprint: bool) -> String {
+    let base = match &key.name {
         Some(name) => name.clone(),
         None => key.host.clone(),
     };
-    key.file_name = sanitize_filename::sanitize(&key.file_name.replace(".", "_").replace(" ", "_"));
-    key.file_name.push_str(".pub");
-    let file_path: std::path::PathBuf = path.join(&key.file_name);
+    // `:` and `%` cover IPv6 addresses and zones: sanitize would squash the
+    // former together, and ssh would expand the latter as a token in IdentityFile.
+    // Wildcards are spelled out so `*.example` doesn't become `_example`.
+    let base = base
+        .replace('*', "star")
+        .replace('?', "qmark")
+        .replace(['.', ' ', ':', '%'], "_");
+    let mut file_name = sanitize_filename::sanitize(&base);
+    if with_fingerprint {
+        let digest = Sha256::digest(key.blob());
+        file_name.push('-');
+        file_name.extend(digest[..4].iter().map(|b| format!("{:02x}", b)));
+    }
+    file_name
+}
 
-    use std::fs::OpenOptions;
-    use std::io::Write;
+/// File names for the distinct key blobs in `keys`.
+///
+/// Keys with several targets share one file, named after the first target.
+/// When different keys would get the same file name (ignoring case, for
+/// case-insensitive filesystems), each of them gets a fingerprint suffix, so
+/// the names don't depend on the order the agent lists keys in.
+fn key_file_names(
+    keys: &[SshKey],
+    naming: KeyFileNaming,
+) -> std::collections::HashMap<String, String> {
+    let mut plain: Vec<(&SshKey, String)> = Vec::new();
+    for key in keys {
+        if !plain.iter().any(|(other, _)| other.key == key.key) {
+            plain.push((key, key_file_name(key, naming, false)));
+        }
+    }
+    let mut names = std::collections::HashMap::new();
+    for (key, name) in &plain {
+        let colliding: Vec<&SshKey> = plain
+            .iter()
+            .filter(|(_, other)| other.eq_ignore_ascii_case(name))
+            .map(|(other, _)| *other)
+            .collect();
+        let name = if colliding.len() > 1 {
+            let name = key_file_name(key, naming, true);
+            println!(
+                "Key file name collision: {} keys would be written to {}, using {}",
+                colliding.len(),
+                key_file_name(key, naming, false),
+                name
+            );
+            name
+        } else {
+            name.clone()
+        };
+        names.insert(key.key.clone(), name);
+    }
+    names
+}
 
-    #[cfg(unix)]
-    use std::os::unix::fs::OpenOptionsExt;
+/// The key file contents: the SSH public key in the format "<type> <base64> <user>@<host>".
+///
+/// Files named by fingerprint carry the full agent comment instead, so the
+/// nickname can still be seen in the file.
+fn key_file_content(key: &SshKey, naming: KeyFileNaming) -> String {
+    match naming {
+        KeyFileNaming::Name => format!("{} {} {}@{}", key.key_type, key.key, key.user, key.host),
+        KeyFileNaming::Fingerprint => format!("{} {} {}", key.key_type, key.key, key.comment),
+    }
+}
+
+/// Quote an ssh_config argument if it contains whitespace or quotes,
+/// escaping the backslashes and double quotes inside it.
+pub(crate) fn quote_config_value(value: &str) -> String {
+    if value.chars().any(|c| c.is_whitespace() || c == '"' || c == '\'') || value.starts_with('#') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
 
-    let mut options = OpenOptions::new();
-    options.write(true).create(true).truncate(true);
+/// The `IdentityAgent` argument to write for `key`, if any.
+fn identity_agent_value(key: &SshKey, mode: IdentityAgentMode) -> Option<String> {
+    let agent = key.agent.as_ref()?;
+    match (mode, agent) {
+        (IdentityAgentMode::Off, _) => None,
+        (IdentityAgentMode::AuthSock, AgentSocket::Env(_)) => {
+            Some(crate::agent_client::SSH_AUTH_SOCK_ENV.to_string())
+        }
+        _ => Some(quote_config_value(&agent.path().display().to_string())),
+    }
+}
 
-    #[cfg(unix)]
-    options.mode(0o600);
+/// One generated `Host` block.
+struct HostEntry<'a> {
+    /// Patterns on the `Host` line.
+    hosts: Vec<&'a str>,
+    /// `HostName` to connect to, for nickname aliases.
+    host_name: Option<&'a str>,
+    /// The key whose user, file and options the block uses.
+    key: &'a SshKey,
+    /// Overrides sections that apply to the block.
+    overrides: Vec<usize>,
+}
 
-    let mut file = options
-        .open(&file_path)
-        .map_err(|e| format!("Failed to open key file {}: {}", file_path.display(), e))?;
+/// Whether two keys would produce identical `Host` blocks apart from the host.
+///
+/// Only hosts that are equally specific are grouped, so every `Host` line
+/// can be ordered as a whole.
+fn same_host_block(a: &SshKey, b: &SshKey) -> bool {
+    a.key == b.key
+        && host_specificity(&a.host) == host_specificity(&b.host)
+        && a.negated_hosts == b.negated_hosts
+        && a.user == b.user
+        && a.port == b.port
+        && a.proxy_jump == b.proxy_jump
+        && a.options == b.options
+        && a.certificate_file == b.certificate_file
+        && a.agent == b.agent
+}
 
-    let key_file_content = format!("{} {} {}@{}", key.key_type, key.key, key.user, key.host);
+/// Build the `Host` blocks for `keys`.
+///
+/// A key with a nickname and a single, literal target gets an alias block
+/// for the nickname as well. Targets of the same key that need identical
+/// blocks are grouped onto one `Host` line.
+///
+/// Overrides naming a host apply to its block, and overrides naming a
+/// nickname apply to every block of that key.
+///
+/// Blocks for literal hosts come first, in key order, followed by pattern
+/// blocks from the most to the least specific, since ssh uses the first
+/// value it finds for each option.
+fn create_host_entries<'a>(keys: &[&'a SshKey], overrides: &Overrides) -> Vec<HostEntry<'a>> {
+    let mut entries: Vec<HostEntry> = Vec::new();
+    for key in keys {
+        let name = key.name.as_deref();
+        let targets = keys.iter().filter(|other| other.key == key.key).count();
+        if let Some(name) = name
+            && targets == 1
+            && !is_host_pattern(&key.host)
+        {
+            entries.push(HostEntry {
+                hosts: vec![name],
+                host_name: Some(&key.host),
+                key,
+                overrides: overrides.sections_for(&[name]),
+            });
+        }
 
-    // if cfg!(debug_assertions) {
-    //     println!(
-    //         "Creating key file {}",
-    //         file_path.display(),
-    //         key_file_content
-    //     );
-    // }
+        let mut names = vec![key.host.as_str()];
+        names.extend(name);
+        let sections = overrides.sections_for(&names);
+        match entries.iter_mut().find(|entry| {
+            entry.host_name.is_none()
+                && same_host_block(entry.key, key)
+                && entry.overrides == sections
+        }) {
+            Some(entry) => entry.hosts.push(&key.host),
+            None => entries.push(HostEntry {
+                hosts: vec![&key.host],
+                host_name: None,
+                key,
+                overrides: sections,
+            }),
+        }
+    }
 
-    file.write_all(key_file_content.as_bytes())
-        .map_err(|e| format!("Failed to write to key file {}: {}", file_path.display(), e))?;
+    // Stable sort, so literal hosts and equally specific patterns keep key order.
+    entries.sort_by_key(|entry| std::cmp::Reverse(host_specificity(entry.hosts[0])));
+    entries
+}
 
+/// Find hosts and nicknames that appear in more than one block and apply
+/// `options.host_conflict`: keep the first block for each, or fail listing them all.
+///
+/// Matching ignores case, like ssh does.
+fn resolve_host_conflicts(entries: &mut Vec<HostEntry>, options: &SyncOptions) -> Result<(), String> {
+    let mut conflicts = Vec::new();
+    for idx in 1..entries.len() {
+        let (earlier, rest) = entries.split_at_mut(idx);
+        let entry = &mut rest[0];
+        entry.hosts.retain(|host| {
+            let Some(first) = earlier
+                .iter()
+                .find(|other| other.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            else {
+                return true;
+            };
+            conflicts.push(format!(
+                "Host {} is used by {:?} ({}) and {:?} ({})",
+                host,
+                first.key.comment,
+                first.key.fingerprint(options.fingerprint_hash),
+                entry.key.comment,
+                entry.key.fingerprint(options.fingerprint_hash),
+            ));
+            false
+        });
+    }
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    if options.host_conflict == HostConflictPolicy::Error {
+        return Err(format!(
+            "Conflicting host blocks, give the keys distinct hosts or nicknames:\n  {}",
+            conflicts.join("\n  ")
+        ));
+    }
+    for conflict in &conflicts {
+        println!("{}; keeping the first", conflict);
+    }
+    entries.retain(|entry| !entry.hosts.is_empty());
     Ok(())
 }
 
-fn create_config_entry(key: &SshKey, key_folder: &std::path::Path) -> String {
-    let key_path = key_folder.join(&key.file_name);
-
-    let mut config = String::new();
+/// Sort rank of a host: literal hosts outrank every pattern.
+fn host_specificity(host: &str) -> usize {
+    if is_host_pattern(host) {
+        pattern_specificity(host)
+    } else {
+        usize::MAX
+    }
+}
 
-    if let Some(ref name) = key.name {
-        config.push_str(&format!("Host {}\n", name));
-        config.push_str(&format!("    HostName {}\n", key.host));
-        config.push_str(&format!("    User {}\n", key.user));
-        config.push_str(&format!("    IdentityFile {}\n", key_path.display()));
-        config.push_str("    IdentitiesOnly yes\n\n");
+fn create_config_entry(
+    entry: &HostEntry,
+    key_folder: &std::path::Path,
+    options: &SyncOptions,
+    overrides: &Overrides,
+    template: &HostTemplate,
+) -> String {
+    let key = entry.key;
+    let mut config = format!("# {}\n", key.fingerprint(options.fingerprint_hash));
+    config.push_str(&format!("Host {}", entry.hosts.join(" ")));
+    // Negations only make sense next to the key's own hosts, not its alias.
+    if entry.host_name.is_none() {
+        for host in &key.negated_hosts {
+            config.push_str(&format!(" !{}", host));
+        }
     }
+    config.push('\n');
 
-    config.push_str(&format!("Host {}\n", key.host));
-    config.push_str(&format!("    User {}\n", key.user));
-    config.push_str(&format!("    IdentityFile {}\n", key_path.display()));
-    config.push_str("    IdentitiesOnly yes\n\n");
+    let mut directives = template.render(|placeholder| match placeholder {
+        "name" => key.name.clone().unwrap_or_default(),
+        "user" => key.user.clone(),
+        "host" => entry.hosts.join(" "),
+        // `%` starts a token in HostName, e.g. in an IPv6 zone like `fe80::1%eth0`.
+        "hostname" => entry
+            .host_name
+            .map(|host_name| host_name.replace('%', "%%"))
+            .unwrap_or_default(),
+        "port" => key.port.map(|port| port.to_string()).unwrap_or_default(),
+        "key_path" => quote_config_value(&key_folder.join(&key.file_name).display().to_string()),
+        "certificate_path" => key
+            .certificate_file
+            .as_ref()
+            .map(|file_name| quote_config_value(&key_folder.join(file_name).display().to_string()))
+            .unwrap_or_default(),
+        "identity_agent" => identity_agent_value(key, options.identity_agent).unwrap_or_default(),
+        "proxy_jump" => key.proxy_jump.clone().unwrap_or_default(),
+        "fingerprint" => key.fingerprint(options.fingerprint_hash),
+        "key_type" => key.key_type.clone(),
+        _ => String::new(),
+    });
+    // Comment options replace template lines with the same keyword.
+    directives.retain(|(keyword, _)| {
+        !key.options
+            .iter()
+            .any(|(option, _)| option.eq_ignore_ascii_case(keyword))
+    });
+    directives.extend(key.options.iter().cloned());
 
+    // Overridden keywords replace the generated lines.
+    let user_directives = overrides.directives(&entry.overrides);
+    directives.retain(|(keyword, _)| {
+        !user_directives
+            .iter()
+            .any(|directive| directive.keyword.eq_ignore_ascii_case(keyword))
+    });
+    directives.extend(
+        user_directives
+            .iter()
+            .map(|directive| (directive.keyword.clone(), directive.value.clone())),
+    );
+
+    for (keyword, value) in directives {
+        config.push_str(&format!("    {} {}\n", keyword, value));
+    }
+    config.push_str("    IdentitiesOnly yes\n\n");
     config
 }
 
-fn write_config_file(config: &str, config_file: &std::path::Path) -> Result<(), String> {
-    use std::fs::OpenOptions;
-    use std::io::Write;
-
-    #[cfg(unix)]
-    use std::os::unix::fs::OpenOptionsExt;
-
-    let mut options = OpenOptions::new();
-    options.write(true).create(true).truncate(true);
-
-    #[cfg(unix)]
-    options.mode(0o600);
+/// Attach each certificate to the plain key it certifies.
+///
+/// Sets `certificate_file` on the matching plain keys and returns the indices
+/// of the certificates that were attached; those don't get host blocks of
+/// their own. Certificates without a matching plain key are left alone and
+/// are written as identities themselves. Certificates that are expired or not
+/// yet valid at `now` (seconds since the Unix epoch) are skipped with a
+/// warning and are returned as well, so they aren't offered to servers.
+fn attach_certificates(keys: &mut [SshKey], now: u64) -> std::collections::HashSet<usize> {
+    let mut attached = std::collections::HashSet::new();
+    for cert_idx in 0..keys.len() {
+        if !keys[cert_idx].is_certificate() {
+            continue;
+        }
+        let Some(certificate) = keys[cert_idx]
+            .public_key()
+            .ok()
+            .and_then(|public_key| public_key.certificate().cloned())
+        else {
+            continue;
+        };
+        if !certificate.is_valid_at(now) {
+            println!(
+                "Certificate {} is expired or not yet valid ({}), skipping it",
+                keys[cert_idx].file_name,
+                certificate.validity()
+            );
+            attached.insert(cert_idx);
+            continue;
+        }
+        let certified_key = base64::engine::general_purpose::STANDARD.encode(&certificate.key_blob);
+        let cert_file = keys[cert_idx].file_name.clone();
 
-    let mut file = options.open(&config_file).map_err(|e| {
-        format!(
-            "Failed to open config file {}: {}",
-            config_file.display(),
-            e
-        )
-    })?;
+        // Every target of the certified key gets the certificate.
+        for key in keys.iter_mut().filter(|key| key.key == certified_key) {
+            match key.certificate_file {
+                Some(ref existing) if *existing != cert_file => {
+                    println!(
+                        "Key {} already has certificate {}, ignoring {}",
+                        key.file_name, existing, cert_file
+                    );
+                    continue;
+                }
+                _ => key.certificate_file = Some(cert_file.clone()),
+            }
+            attached.insert(cert_idx);
+        }
+    }
+    attached
+}
 
+/// Write `config` to `config_file` atomically; see [`write_atomic`](crate::fsutil::write_atomic).
+fn write_config_file(config: &str, config_file: &std::path::Path) -> Result<(), String> {
     println!("Writing to ssh config file {}", config_file.display());
-    file.write_all(config.as_bytes()).map_err(|e| {
+    crate::fsutil::write_atomic(config_file, config.as_bytes(), 0o600).map_err(|e| {
         format!(
             "Failed to write to config file {}: {}",
             config_file.display(),
             e
         )
-    })?;
-    Ok(())
+    })
 }
 
-fn check_base_config_needs_editing() -> bool {
-    println!("Checking if SSH base config needs editing");
-    use std::io::{BufRead, BufReader};
+/// Format the `Host *` block, or nothing when there are no directives.
+fn format_global_block(directives: &[(String, String)]) -> String {
+    if directives.is_empty() {
+        return String::new();
+    }
+    let mut block = "Host *\n".to_string();
+    for (keyword, value) in directives {
+        block.push_str(&format!("    {} {}\n", keyword, value));
+    }
+    block.push('\n');
+    block
+}
 
+/// Warn about keywords set both by our `Host *` block and by a section of
+/// the base config that applies to every host. Our file is included at the
+/// top, so ssh uses our value and silently ignores the user's.
+fn warn_global_defaults_conflicts(directives: &[(String, String)]) {
     let Some(base_config_path) = crate::constants::ssh_base_config_file_path() else {
-        return true;
+        return;
     };
-
-    let Some(include_line) = crate::constants::ssh_base_include_line() else {
-        return true;
+    // A broken base config is reported when checking for the Include line.
+    let Ok(Some(base_config)) = SshConfig::load_from(&base_config_path) else {
+        return;
     };
 
-    let Ok(file) = std::fs::File::open(&base_config_path) else {
-        return true; // File doesn't exist, needs editing
-    };
-
-    let reader = BufReader::new(file);
-    let include_trimmed = include_line.trim();
-
-    for line in reader.lines().take(20) {
-        // Only check first 20 lines
-        if let Ok(line) = line {
-            if line.contains(include_trimmed) {
-                return false; // Include line found, no editing needed
-            }
+    for (scope, line) in base_config.directives() {
+        if !scope.applies_to_all() || line.is("include") {
+            continue;
+        }
+        if let Some((ours, our_value)) = directives.iter().find(|(ours, _)| line.is(ours)) {
+            println!(
+                "Warning: {} line {} sets {} {}, but the generated global defaults set {} {} first",
+                base_config_path.display(),
+                line.line_number,
+                line.keyword,
+                line.value,
+                ours,
+                our_value
+            );
         }
     }
-
-    true // Include line not found in first 20 lines
 }
 
-fn edit_base_config() -> Result<(), String> {
+/// Whether the base config still lacks an `Include` of the generated config.
+///
+/// The `Include` counts only where it applies to every host: before the
+/// first `Host` or `Match` line, or under `Host *` or `Match all`. Paths may
+/// start with `~/`, be relative to `~/.ssh` or use glob(3) wildcards, as in ssh.
+fn check_base_config_needs_editing() -> Result<bool, String> {
+    println!("Checking if SSH base config needs editing");
+
     let base_config_path = crate::constants::ssh_base_config_file_path()
         .ok_or_else(|| "Failed to determine SSH base config file path".to_string())?;
+    let config_file = crate::constants::ssh_config_file_path()
+        .ok_or_else(|| "Failed to determine SSH config file path".to_string())?;
+    let ssh_dir = crate::constants::ssh_dir()
+        .ok_or_else(|| "Failed to determine SSH directory".to_string())?;
 
+    let Some(base_config) = SshConfig::load_from(&base_config_path)
+        .map_err(|e| format!("Failed to parse SSH base config: {}", e))?
+    else {
+        return Ok(true); // File doesn't exist, needs editing
+    };
+
+    let included = base_config
+        .directives()
+        .filter(|(scope, line)| scope.applies_to_all() && line.is("include"))
+        .flat_map(|(_, line)| &line.args)
+        .any(|arg| {
+            let path = crate::settings::expand_tilde(arg);
+            let path = if path.is_absolute() { path } else { ssh_dir.join(path) };
+            path_glob_match(&path, &config_file)
+        });
+    Ok(!included)
+}
+
+/// The base config with our `Include` line added at the top.
+fn edited_base_config(base_config_path: &std::path::Path) -> Result<(Option<String>, String), String> {
     let include_line = crate::constants::ssh_base_include_line()
         .ok_or_else(|| "Failed to determine SSH base config include line".to_string())?;
 
@@ -174,36 +819,159 @@ fn edit_base_config() -> Result<(), String> {
     content.push_str("\n\n");
 
     // Append existing content if file exists
-    if base_config_path.exists() {
-        let existing_content = std::fs::read_to_string(&base_config_path).map_err(|e| {
-            format!(
-                "Failed to read SSH base config file {}: {}",
-                base_config_path.display(),
-                e
-            )
-        })?;
-        content.push_str(&existing_content);
+    let existing_content = read_existing(base_config_path).map_err(|e| {
+        format!(
+            "Failed to read SSH base config file {}: {}",
+            base_config_path.display(),
+            e
+        )
+    })?;
+    if let Some(ref existing_content) = existing_content {
+        content.push_str(existing_content);
+    }
+    Ok((existing_content, content))
+}
+
+/// Back up the base config, then write its new contents.
+fn edit_base_config(base_config_path: &std::path::Path, content: &str) -> Result<(), String> {
+    // Keep a copy of the user's config before we touch it.
+    let backup = crate::fsutil::backup_with_rotation(
+        base_config_path,
+        crate::constants::SSH_BASE_CONFIG_BACKUP_SUFFIX,
+        crate::constants::SSH_BASE_CONFIG_BACKUPS_KEPT,
+    )
+    .map_err(|e| {
+        format!(
+            "Failed to back up SSH base config file {}: {}",
+            base_config_path.display(),
+            e
+        )
+    })?;
+    if let Some(backup) = backup {
+        println!("Backed up SSH base config to {}", backup.display());
+    }
+
+    write_config_file(content, base_config_path)
+}
+
+/// Contents of `path`, or `None` if it doesn't exist.
+fn read_existing(path: &std::path::Path) -> std::io::Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
     }
+}
 
-    write_config_file(&content, &base_config_path)
+/// Which file a planned change is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A `.pub` file in the key folder.
+    KeyFile,
+    /// The generated `config.ssh_agent_sync`.
+    SyncConfig,
+    /// The user's `~/.ssh/config`.
+    BaseConfig,
+    /// The list of key files the tool owns, in the key folder.
+    Manifest,
 }
 
-pub fn add_keys_to_config(keys: &mut Vec<SshKey>, force: bool) -> Result<(), String> {
-    println!("Getting stored ssh keys CRC");
-    let new_crc = generate_ssh_keys_crc(keys);
-    let current_crc = get_current_ssh_keys_crc();
+/// What a sync will do to one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAction {
+    Create,
+    Overwrite,
+    Delete,
+}
 
-    if !force && current_crc.is_some() {
-        let crc = current_crc.unwrap();
-        if crc == new_crc {
-            println!("Skipping: CRCs match");
-            return Ok(());
-        }
+/// A single planned file change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: std::path::PathBuf,
+    pub kind: FileKind,
+    pub action: FileAction,
+    /// Current contents, for overwrites and deletions.
+    pub old: Option<String>,
+    /// New contents, for creations and overwrites.
+    pub new: Option<String>,
+}
+
+impl FileChange {
+    /// Plan writing `new` to `path`, or nothing if it already holds exactly that.
+    fn write(
+        path: std::path::PathBuf,
+        kind: FileKind,
+        old: Option<String>,
+        new: String,
+    ) -> Option<Self> {
+        let action = match old {
+            Some(ref old) if *old == new => return None,
+            Some(_) => FileAction::Overwrite,
+            None => FileAction::Create,
+        };
+        Some(Self {
+            path,
+            kind,
+            action,
+            old,
+            new: Some(new),
+        })
     }
+}
+
+/// Everything a sync would change on disk, computed without writing anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Folder holding the key files; created when the plan is applied.
+    pub key_folder: std::path::PathBuf,
+    pub changes: Vec<FileChange>,
+    /// The keys and settings are the same as at the last sync, but its
+    /// files were edited or deleted since, so `changes` undoes those edits.
+    pub modified_on_disk: bool,
+}
+
+impl SyncPlan {
+    /// Whether the plan leaves everything as it is.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Work out how the config and key files must change for `keys`, without
+/// touching disk.
+///
+/// Sets `file_name` (and `certificate_file`) on the keys. Returns an empty
+/// plan if the stored CRC shows nothing changed, unless `options.force` is set.
+pub fn plan_sync(keys: &mut [SshKey], options: &SyncOptions) -> Result<SyncPlan, String> {
+    let overrides = Overrides::load()?;
+    let template = match options.host_template {
+        Some(ref path) => HostTemplate::load_from(path)?,
+        None => HostTemplate::default(),
+    };
+    let global_defaults = options.global_defaults.directives()?;
+    let global_block = format_global_block(&global_defaults);
+
+    let digest = generate_sync_digest(keys, options, &overrides, &template, &global_block);
 
     // Resolve the SSH config key folder path or return an error if it cannot be determined.
     let key_folder = crate::constants::ssh_config_key_folder_path()
         .ok_or_else(|| "Failed to determine SSH config key folder path".to_string())?;
+    let mut plan = SyncPlan {
+        key_folder: key_folder.clone(),
+        changes: Vec::new(),
+        modified_on_disk: false,
+    };
+
+    println!("Checking stored sync digest");
+    let intact = last_sync_intact(&digest, &key_folder);
+    if !options.force && intact == Some(true) {
+        println!("Skipping: nothing changed since the last sync");
+        return Ok(plan);
+    }
+    if intact == Some(false) {
+        println!("Generated files were changed on disk");
+        plan.modified_on_disk = true;
+    }
 
     // If the path exists but is not a directory, return an error.
     if key_folder.exists() && !key_folder.is_dir() {
@@ -213,43 +981,706 @@ pub fn add_keys_to_config(keys: &mut Vec<SshKey>, force: bool) -> Result<(), Str
         ));
     }
 
+    warn_global_defaults_conflicts(&global_defaults);
+
+    let key_files = key_file_names(keys, options.key_file_naming);
+    for key in keys.iter_mut() {
+        key.file_name = key_files[&key.key].clone();
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let attached_certificates = attach_certificates(keys, now);
+    let host_keys: Vec<&SshKey> = keys
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !attached_certificates.contains(idx))
+        .map(|(_, key)| key)
+        .collect();
+    let mut entries = create_host_entries(&host_keys, &overrides);
+    resolve_host_conflicts(&mut entries, options)?;
+    for (idx, section) in overrides.sections.iter().enumerate() {
+        if !entries.iter().any(|entry| entry.overrides.contains(&idx)) {
+            println!(
+                "Overrides for {} match no synced host or nickname",
+                section.hosts.join(" ")
+            );
+        }
+    }
+
+    // Only write the key files the config points at: a key whose every block
+    // lost a host conflict, or an expired certificate, gets none.
+    let mut owned: Vec<String> = Vec::new();
+    for entry in &entries {
+        owned.push(entry.key.file_name.clone());
+        owned.extend(entry.key.certificate_file.clone());
+    }
+    owned.sort();
+    owned.dedup();
+    let mut key_file_contents: Vec<(String, String)> = Vec::new();
+    for key in keys.iter() {
+        if !owned.contains(&key.file_name)
+            || key_file_contents.iter().any(|(file_name, _)| *file_name == key.file_name)
+        {
+            continue;
+        }
+        let content = key_file_content(key, options.key_file_naming);
+        key_file_contents.push((key.file_name.clone(), content.clone()));
+
+        let path = key_folder.join(&key.file_name);
+        let old = read_existing(&path)
+            .map_err(|e| format!("Failed to read key file {}: {}", path.display(), e))?;
+        plan.changes.extend(FileChange::write(path, FileKind::KeyFile, old, content));
+    }
+    key_file_contents.sort();
+
+    let mut ssh_config = String::new();
+    for entry in &entries {
+        let config_entry = create_config_entry(entry, &key_folder, options, &overrides, &template);
+        ssh_config.push_str(&config_entry);
+    }
+    // ssh takes the first value for each keyword, so the defaults go last
+    // to leave the host blocks' own values in effect.
+    ssh_config.push_str(&global_block);
+    let ssh_config = format!(
+        "{}{}\n{}{}\n\n{}",
+        crate::constants::SSH_AGENT_SYNC_CRC_PREFIX,
+        digest,
+        crate::constants::SSH_AGENT_SYNC_OUTPUT_PREFIX,
+        generate_output_digest(&ssh_config, &key_file_contents),
+        ssh_config
+    );
+
+    let config_file = crate::constants::ssh_config_file_path()
+        .ok_or_else(|| "Failed to determine SSH config file path".to_string())?;
+    let old = read_existing(&config_file)
+        .map_err(|e| format!("Failed to read config file {}: {}", config_file.display(), e))?;
+    plan.changes
+        .extend(FileChange::write(config_file, FileKind::SyncConfig, old, ssh_config));
+
+    if check_base_config_needs_editing()? {
+        let base_config_path = crate::constants::ssh_base_config_file_path()
+            .ok_or_else(|| "Failed to determine SSH base config file path".to_string())?;
+        let (old, new) = edited_base_config(&base_config_path)?;
+        plan.changes
+            .extend(FileChange::write(base_config_path, FileKind::BaseConfig, old, new));
+    }
+
+    // Only files listed in the manifest are ours to remove, and only once the
+    // new config no longer points at them.
+    let manifest_path = crate::constants::ssh_key_manifest_file_path()
+        .ok_or_else(|| "Failed to determine key manifest path".to_string())?;
+    let old_manifest = read_existing(&manifest_path)
+        .map_err(|e| format!("Failed to read key manifest {}: {}", manifest_path.display(), e))?;
+    for file_name in parse_manifest(old_manifest.as_deref().unwrap_or_default()) {
+        if owned.contains(&file_name) {
+            continue;
+        }
+        let path = key_folder.join(&file_name);
+        let old = read_existing(&path)
+            .map_err(|e| format!("Failed to read key file {}: {}", path.display(), e))?;
+        if let Some(old) = old {
+            plan.changes.push(FileChange {
+                path,
+                kind: FileKind::KeyFile,
+                action: FileAction::Delete,
+                old: Some(old),
+                new: None,
+            });
+        }
+    }
+    if old_manifest.is_some() || !owned.is_empty() {
+        plan.changes.extend(FileChange::write(
+            manifest_path,
+            FileKind::Manifest,
+            old_manifest,
+            format_manifest(&owned),
+        ));
+    }
+
+    Ok(plan)
+}
+
+/// Header of the key folder manifest.
+const MANIFEST_HEADER: &str = "# Key files created by ssh-agent-sync. Files listed here are removed\n# once their key is no longer synced.\n";
+
+fn format_manifest(file_names: &[String]) -> String {
+    let mut content = MANIFEST_HEADER.to_string();
+    for file_name in file_names {
+        content.push_str(file_name);
+        content.push('\n');
+    }
+    content
+}
+
+/// Key file names listed in a manifest. Anything that isn't a plain `.pub`
+/// file name in the key folder is ignored, so a damaged manifest can't make
+/// us delete files elsewhere.
+fn parse_manifest(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| {
+            line.ends_with(".pub")
+                && !line.starts_with('.')
+                && !line.contains(['/', '\\'])
+                && sanitize_filename::sanitize(line) == *line
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// Carry out a plan from [`plan_sync`].
+pub fn apply_sync_plan(plan: &SyncPlan) -> Result<(), String> {
+    let needs_key_folder = plan.changes.iter().any(|change| {
+        matches!(change.kind, FileKind::KeyFile | FileKind::Manifest)
+            && change.action != FileAction::Delete
+    });
     // Create the folder if it does not exist.
-    if !key_folder.exists() {
-        std::fs::create_dir_all(&key_folder).map_err(|e| {
+    if needs_key_folder && !plan.key_folder.exists() {
+        std::fs::create_dir_all(&plan.key_folder).map_err(|e| {
             format!(
                 "Failed to create key folder {}: {}",
-                key_folder.display(),
+                plan.key_folder.display(),
                 e
             )
         })?;
     }
 
-    let mut ssh_config = format!(
-        "{}{}\n\n",
-        crate::constants::SSH_AGENT_SYNC_CRC_PREFIX,
-        new_crc
-    );
-    ssh_config.push_str("Host *\n    IdentitiesOnly yes\n\n");
-    println!("Creating {} key files in ssh config key folder", keys.len());
-    for key in keys.iter_mut() {
-        // Fail fast if we cannot create a key file.
-        if let Err(e) = create_key_file(key, &key_folder) {
-            return Err(e);
+    let key_file_count = plan
+        .changes
+        .iter()
+        .filter(|change| change.kind == FileKind::KeyFile && change.action != FileAction::Delete)
+        .count();
+    if key_file_count > 0 {
+        println!("Writing {} key files in ssh config key folder", key_file_count);
+    }
+
+    for change in &plan.changes {
+        let path = &change.path;
+        match (change.action, change.kind, change.new.as_deref()) {
+            (FileAction::Delete, _, _) => {
+                println!("Removing {}", path.display());
+                std::fs::remove_file(path)
+                    .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            }
+            (_, FileKind::KeyFile, Some(content)) => {
+                // Fail fast if we cannot create a key file.
+                crate::fsutil::write_atomic(path, content.as_bytes(), 0o600).map_err(|e| {
+                    format!("Failed to write to key file {}: {}", path.display(), e)
+                })?;
+            }
+            (_, FileKind::SyncConfig, Some(content)) => write_config_file(content, path)?,
+            (_, FileKind::Manifest, Some(content)) => {
+                crate::fsutil::write_atomic(path, content.as_bytes(), 0o600).map_err(|e| {
+                    format!("Failed to write key manifest {}: {}", path.display(), e)
+                })?;
+            }
+            (_, FileKind::BaseConfig, Some(content)) => edit_base_config(path, content)?,
+            (_, _, None) => {}
         }
-        let config_entry = create_config_entry(key, &key_folder);
-        // println!("Config entry for host {}:\n{}", key.host, config_entry);
-        ssh_config.push_str(&config_entry);
     }
+    Ok(())
+}
 
-    let config_file = crate::constants::ssh_config_file_path()
-        .ok_or_else(|| "Failed to determine SSH config file path".to_string())?;
-    write_config_file(&ssh_config, &config_file)?;
+/// Print a plan as colored unified diffs, like `diff -u` for every file.
+pub fn print_sync_plan(plan: &SyncPlan) {
+    use crate::diff::{DiffLine, unified_diff};
+    use colored::Colorize;
 
-    let edit_base = check_base_config_needs_editing();
+    if plan.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for change in &plan.changes {
+        let (verb, old_label, new_label) = match change.action {
+            FileAction::Create => ("create", "/dev/null".to_string(), change.path.display().to_string()),
+            FileAction::Overwrite => (
+                "overwrite",
+                change.path.display().to_string(),
+                change.path.display().to_string(),
+            ),
+            FileAction::Delete => ("delete", change.path.display().to_string(), "/dev/null".to_string()),
+        };
+        println!("{}", format!("# {} {}", verb, change.path.display()).bold());
+        println!("{}", format!("--- {}", old_label).bold());
+        println!("{}", format!("+++ {}", new_label).bold());
+        let old = change.old.as_deref().unwrap_or_default();
+        let new = change.new.as_deref().unwrap_or_default();
+        for line in unified_diff(old, new) {
+            match line {
+                DiffLine::Hunk(header) => println!("{}", header.cyan()),
+                DiffLine::Context(text) => println!(" {}", text),
+                DiffLine::Removed(text) => println!("{}", format!("-{}", text).red()),
+                DiffLine::Added(text) => println!("{}", format!("+{}", text).green()),
+            }
+        }
+    }
+    let count = plan.changes.len();
+    println!("{} file{} would change", count, if count == 1 { "" } else { "s" });
+}
+
+/// Compare the generated files on disk with what the current keys and
+/// settings should produce, ignoring the stored digest.
+///
+/// The returned plan lists every difference; applying it repairs them.
+pub fn verify_sync(keys: &mut [SshKey], options: &SyncOptions) -> Result<SyncPlan, String> {
+    let options = SyncOptions {
+        force: true,
+        ..options.clone()
+    };
+    plan_sync(keys, &options)
+}
 
-    if edit_base {
-        edit_base_config()?;
+pub fn add_keys_to_config(keys: &mut [SshKey], options: &SyncOptions) -> Result<(), String> {
+    let plan = plan_sync(keys, options)?;
+    apply_sync_plan(&plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{FakeAgent, get_ssh_keys_from};
+    use crate::agent::PublicKeyList;
+    use crate::testutil::{ED25519_CERT, ED25519_KEY, TempDir, ed25519_blob};
+
+    /// Keys for the given comments, one distinct key per comment.
+    fn keys(comments: &[&str]) -> Vec<SshKey> {
+        let agent = comments
+            .iter()
+            .enumerate()
+            .fold(FakeAgent::new(), |agent, (idx, comment)| {
+                agent.with_identity(ed25519_blob(idx as u8), comment)
+            });
+        get_ssh_keys_from(&agent).unwrap().keys
     }
 
-    Ok(())
+    #[test]
+    fn key_file_names_from_host_or_nickname() {
+        let keys = keys(&[
+            "git@github.com, git@gitlab.com",
+            "Work <me@work.example>",
+            "ops@*.prod",
+        ]);
+        let names = key_file_names(&keys, KeyFileNaming::Name);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[&keys[0].key], "github_com.pub");
+        assert_eq!(names[&keys[2].key], "Work.pub");
+        assert_eq!(names[&keys[3].key], "star_prod.pub");
+    }
+
+    #[test]
+    fn key_file_name_collisions_get_fingerprint_suffixes() {
+        let keys = keys(&["a@GitHub.com", "b@github.com", "c@other.example"]);
+        let names = key_file_names(&keys, KeyFileNaming::Name);
+        let (first, second) = (&names[&keys[0].key], &names[&keys[1].key]);
+        assert_ne!(first.to_ascii_lowercase(), second.to_ascii_lowercase());
+        assert!(first.starts_with("GitHub_com-") && first.ends_with(".pub"), "{}", first);
+        assert!(second.starts_with("github_com-") && second.ends_with(".pub"), "{}", second);
+        assert_eq!(names[&keys[2].key], "other_example.pub");
+
+        // The suffixes don't depend on the order of the keys.
+        let reversed: Vec<SshKey> = keys.iter().rev().cloned().collect();
+        assert_eq!(key_file_names(&reversed, KeyFileNaming::Name), names);
+    }
+
+    #[test]
+    fn key_file_names_by_fingerprint() {
+        let keys = keys(&["a@one", "b@one"]);
+        let names = key_file_names(&keys, KeyFileNaming::Fingerprint);
+        assert!(names[&keys[0].key].starts_with("SHA256_"));
+        assert_ne!(names[&keys[0].key], names[&keys[1].key]);
+    }
+
+    #[test]
+    fn host_conflict_keeps_first_block() {
+        let keys = keys(&["a@github.com", "b@github.com, b@gitlab.com", "c@GITHUB.COM"]);
+        let key_refs: Vec<&SshKey> = keys.iter().collect();
+        let mut entries = create_host_entries(&key_refs, &Overrides::default());
+        resolve_host_conflicts(&mut entries, &SyncOptions::default()).unwrap();
+        let blocks: Vec<(&str, Vec<&str>)> = entries
+            .iter()
+            .map(|entry| (entry.key.user.as_str(), entry.hosts.clone()))
+            .collect();
+        assert_eq!(blocks, [("a", vec!["github.com"]), ("b", vec!["gitlab.com"])]);
+    }
+
+    #[test]
+    fn host_conflict_error_lists_every_conflict() {
+        let keys = keys(&["a@github.com", "b@github.com", "c@github.com"]);
+        let key_refs: Vec<&SshKey> = keys.iter().collect();
+        let mut entries = create_host_entries(&key_refs, &Overrides::default());
+        let options = SyncOptions {
+            host_conflict: HostConflictPolicy::Error,
+            ..SyncOptions::default()
+        };
+        let err = resolve_host_conflicts(&mut entries, &options).unwrap_err();
+        assert_eq!(err.matches("Host github.com is used by").count(), 2, "{}", err);
+    }
+
+    #[test]
+    fn keys_without_blocks_get_no_key_file() {
+        let dir = TempDir::new();
+        dir.use_as_ssh_dir();
+        let mut keys = keys(&["a@github.com", "b@github.com"]);
+        let plan = plan_sync(&mut keys, &SyncOptions::default()).unwrap();
+
+        let key_files: Vec<_> = plan
+            .changes
+            .iter()
+            .filter(|change| change.kind == FileKind::KeyFile)
+            .map(|change| change.path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(key_files, [keys[0].file_name.as_str()]);
+        let manifest = plan
+            .changes
+            .iter()
+            .find(|change| change.kind == FileKind::Manifest)
+            .and_then(|change| change.new.as_deref())
+            .unwrap();
+        assert_eq!(parse_manifest(manifest), [keys[0].file_name.clone()]);
+    }
+
+    #[test]
+    fn identity_agent_modes() {
+        let mut keys = keys(&["a@env", "b@path", "c@file"]);
+        keys[0].agent = Some(AgentSocket::Env("/tmp/ssh-XYZ/agent.1".into()));
+        keys[1].agent = Some(AgentSocket::Path("/home/me/.1password/agent.sock".into()));
+        let values = |mode| -> Vec<_> { keys.iter().map(|key| identity_agent_value(key, mode)).collect() };
+
+        assert_eq!(values(IdentityAgentMode::Off), [None, None, None]);
+        assert_eq!(
+            values(IdentityAgentMode::AuthSock),
+            [
+                Some("SSH_AUTH_SOCK".to_string()),
+                Some("/home/me/.1password/agent.sock".to_string()),
+                None
+            ]
+        );
+        assert_eq!(
+            values(IdentityAgentMode::Path),
+            [
+                Some("/tmp/ssh-XYZ/agent.1".to_string()),
+                Some("/home/me/.1password/agent.sock".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_config_values() {
+        assert_eq!(quote_config_value("/plain/path"), "/plain/path");
+        assert_eq!(
+            quote_config_value("/Library/Group Containers/agent.sock"),
+            "\"/Library/Group Containers/agent.sock\""
+        );
+        assert_eq!(quote_config_value("a\"b c"), r#""a\"b c""#);
+        assert_eq!(quote_config_value(r"C:\my keys\k.pub"), r#""C:\\my keys\\k.pub""#);
+        assert_eq!(quote_config_value("it's"), "\"it's\"");
+        assert_eq!(quote_config_value("#x"), "\"#x\"");
+    }
+
+    /// A key and its certificate, in that order, with file names set.
+    fn key_and_certificate() -> Vec<SshKey> {
+        let lines = format!(
+            "ssh-ed25519 {} dev@host\nssh-ed25519-cert-v01@openssh.com {} dev@host\n",
+            ED25519_KEY, ED25519_CERT
+        );
+        let list = PublicKeyList::from_reader(lines.as_bytes(), "test").unwrap();
+        let mut keys = get_ssh_keys_from(&list).unwrap().keys;
+        keys[0].file_name = "dev-key".to_string();
+        keys[1].file_name = "dev-cert".to_string();
+        keys
+    }
+
+    #[test]
+    fn attaches_valid_certificate() {
+        let mut keys = key_and_certificate();
+        let attached = attach_certificates(&mut keys, 1800000000);
+        assert_eq!(attached, [1].into());
+        assert_eq!(keys[0].certificate_file.as_deref(), Some("dev-cert"));
+    }
+
+    #[test]
+    fn skips_expired_and_future_certificates() {
+        for now in [1700000000, 1900000000] {
+            let mut keys = key_and_certificate();
+            let attached = attach_certificates(&mut keys, now);
+            assert_eq!(attached, [1].into());
+            assert_eq!(keys[0].certificate_file, None);
+        }
+    }
+
+    #[test]
+    fn groups_targets_of_one_key() {
+        let keys = keys(&["Deploy <git@{a,b}.example>", "ci@build:2222, ci@other", "Box <me@box>"]);
+        let key_refs: Vec<&SshKey> = keys.iter().collect();
+        let hosts: Vec<_> = create_host_entries(&key_refs, &Overrides::default())
+            .iter()
+            .map(|entry| (entry.hosts.join(" "), entry.host_name))
+            .collect();
+        assert_eq!(
+            hosts,
+            [
+                ("a.example b.example".to_string(), None),
+                ("build".to_string(), None),
+                ("other".to_string(), None),
+                ("Box".to_string(), Some("box")),
+                ("box".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_percent_in_host_name() {
+        let keys = keys(&["Router <admin@[fe80::1%eth0]>"]);
+        let entry = HostEntry {
+            hosts: vec!["Router"],
+            host_name: Some(&keys[0].host),
+            key: &keys[0],
+            overrides: Vec::new(),
+        };
+        let config = create_config_entry(
+            &entry,
+            std::path::Path::new("/keys"),
+            &SyncOptions::default(),
+            &Overrides::default(),
+            &HostTemplate::default(),
+        );
+        assert!(config.contains("    HostName fe80::1%%eth0\n"), "{}", config);
+    }
+
+    #[test]
+    fn orders_patterns_after_literal_hosts() {
+        let keys = keys(&["ops@*.example", "ops@*.prod.example", "Db <ops@db.example>"]);
+        let key_refs: Vec<&SshKey> = keys.iter().collect();
+        let hosts: Vec<_> = create_host_entries(&key_refs, &Overrides::default())
+            .iter()
+            .map(|entry| entry.hosts.join(" "))
+            .collect();
+        assert_eq!(hosts, ["Db", "db.example", "*.prod.example", "*.example"]);
+    }
+
+    #[test]
+    fn config_entry_quotes_key_paths() {
+        let mut keys = keys(&["git@github.com"]);
+        keys[0].file_name = "github_com.pub".to_string();
+        let host_keys: Vec<&SshKey> = keys.iter().collect();
+        let overrides = Overrides::default();
+        let entries = create_host_entries(&host_keys, &overrides);
+        let entry = create_config_entry(
+            &entries[0],
+            std::path::Path::new("/home/a b/.ssh/keys"),
+            &SyncOptions::default(),
+            &overrides,
+            &HostTemplate::default(),
+        );
+        assert!(
+            entry.contains("    IdentityFile \"/home/a b/.ssh/keys/github_com.pub\"\n"),
+            "{}",
+            entry
+        );
+        assert!(entry.starts_with("# SHA256:"));
+        assert!(entry.contains("Host github.com\n"));
+        assert!(entry.ends_with("    IdentitiesOnly yes\n\n"));
+    }
+
+    #[test]
+    fn overrides_replace_generated_lines() {
+        let keys = keys(&["Box <me@box.example:2222 ForwardAgent=no>", "git@github.com"]);
+        let host_keys: Vec<&SshKey> = keys.iter().collect();
+        let overrides = Overrides::parse("Host Box\n  Port 2200\n  User admin\n").unwrap();
+        let entries = create_host_entries(&host_keys, &overrides);
+        let config: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                create_config_entry(
+                    entry,
+                    std::path::Path::new("/keys"),
+                    &SyncOptions::default(),
+                    &overrides,
+                    &HostTemplate::default(),
+                )
+            })
+            .collect();
+        // Overrides for a nickname apply to every block of that key.
+        for entry in &config[..2] {
+            assert!(entry.contains("    Port 2200\n"), "{}", entry);
+            assert!(entry.contains("    User admin\n"), "{}", entry);
+            assert!(entry.contains("    ForwardAgent no\n"), "{}", entry);
+            assert!(!entry.contains("2222") && !entry.contains("User me"), "{}", entry);
+        }
+        assert!(config[2].contains("    User git\n"), "{}", config[2]);
+        assert!(!config[2].contains("Port"), "{}", config[2]);
+    }
+
+    #[test]
+    fn global_defaults_on_and_off() {
+        assert_eq!(
+            GlobalDefaults::On.directives().unwrap(),
+            [("IdentitiesOnly".to_string(), "yes".to_string())]
+        );
+        assert_eq!(GlobalDefaults::Off.directives().unwrap(), []);
+        assert_eq!(format_global_block(&[]), "");
+        assert_eq!(
+            format_global_block(&GlobalDefaults::On.directives().unwrap()),
+            "Host *\n    IdentitiesOnly yes\n\n"
+        );
+    }
+
+    #[test]
+    fn global_defaults_from_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("defaults");
+        std::fs::write(&path, "# shared\nServerAliveInterval 30\n\nAddKeysToAgent=yes\n").unwrap();
+        assert_eq!(
+            GlobalDefaults::Custom(path).directives().unwrap(),
+            [
+                ("ServerAliveInterval".to_string(), "30".to_string()),
+                ("AddKeysToAgent".to_string(), "yes".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn global_defaults_reject_blocks_and_unknown_keywords() {
+        let dir = TempDir::new();
+        let path = dir.path().join("defaults");
+        for (content, message) in [
+            ("ServerAliveInterval 30\nHost db\n", "line 2: `Host` can't be used in the global defaults"),
+            ("Match all\n", "line 1: `Match` can't be used in the global defaults"),
+            ("Colour blue\n", "line 1: unknown ssh_config keyword `Colour`"),
+            ("Port\n", "line 1: `Port` has no value"),
+        ] {
+            std::fs::write(&path, content).unwrap();
+            let error = GlobalDefaults::Custom(path.clone()).directives().unwrap_err();
+            assert_eq!(error, format!("{}: {}", path.display(), message));
+        }
+        let missing = GlobalDefaults::Custom(dir.path().join("missing")).directives();
+        assert!(missing.unwrap_err().starts_with("Failed to read global defaults"));
+    }
+
+    #[test]
+    fn sync_deletes_only_stale_files_it_wrote() {
+        let dir = TempDir::new();
+        dir.use_as_ssh_dir();
+        let options = SyncOptions::default();
+
+        let mut old_keys = keys(&["a@one", "b@two"]);
+        apply_sync_plan(&plan_sync(&mut old_keys, &options).unwrap()).unwrap();
+        let key_folder = crate::constants::ssh_config_key_folder_path().unwrap();
+        let kept = key_folder.join(&old_keys[0].file_name);
+        let stale = key_folder.join(&old_keys[1].file_name);
+        let foreign = key_folder.join("mine.pub");
+        std::fs::write(&foreign, "not ours\n").unwrap();
+
+        let mut new_keys = keys(&["a@one"]);
+        let plan = plan_sync(&mut new_keys, &options).unwrap();
+        let deleted: Vec<_> = plan
+            .changes
+            .iter()
+            .filter(|change| change.action == FileAction::Delete)
+            .map(|change| change.path.as_path())
+            .collect();
+        assert_eq!(deleted, [stale.as_path()]);
+
+        apply_sync_plan(&plan).unwrap();
+        assert!(kept.exists());
+        assert!(!stale.exists());
+        assert_eq!(std::fs::read_to_string(&foreign).unwrap(), "not ours\n");
+    }
+
+    #[test]
+    fn digest_ignores_agent_path_unless_written() {
+        let mut keys = keys(&["git@github.com"]);
+        let digest = |keys: &[SshKey], identity_agent| {
+            let options = SyncOptions {
+                identity_agent,
+                ..SyncOptions::default()
+            };
+            let template = HostTemplate::default();
+            generate_sync_digest(keys, &options, &Overrides::default(), &template, "")
+        };
+        keys[0].agent = Some(AgentSocket::Path("/run/one.sock".into()));
+        let off = digest(&keys, IdentityAgentMode::Off);
+        let path = digest(&keys, IdentityAgentMode::Path);
+        keys[0].agent = Some(AgentSocket::Path("/run/two.sock".into()));
+        assert_eq!(digest(&keys, IdentityAgentMode::Off), off);
+        assert_ne!(digest(&keys, IdentityAgentMode::Path), path);
+    }
+
+    #[test]
+    fn unchanged_sync_is_skipped() {
+        let dir = TempDir::new();
+        dir.use_as_ssh_dir();
+        let options = SyncOptions::default();
+        for comments in [&["a@one", "b@two"][..], &[]] {
+            let mut keys = keys(comments);
+            apply_sync_plan(&plan_sync(&mut keys, &options).unwrap()).unwrap();
+            assert!(plan_sync(&mut keys, &options).unwrap().is_empty(), "{:?}", comments);
+        }
+    }
+
+    #[test]
+    fn verify_finds_edited_and_deleted_files() {
+        let dir = TempDir::new();
+        dir.use_as_ssh_dir();
+        let options = SyncOptions::default();
+        let mut keys = keys(&["a@one", "b@two"]);
+        apply_sync_plan(&plan_sync(&mut keys, &options).unwrap()).unwrap();
+
+        let plan = verify_sync(&mut keys, &options).unwrap();
+        assert!(plan.is_empty(), "{:?}", plan.changes);
+        assert!(!plan.modified_on_disk);
+
+        let config_file = crate::constants::ssh_config_file_path().unwrap();
+        let mut config = std::fs::read_to_string(&config_file).unwrap();
+        config.push_str("Host extra\n");
+        std::fs::write(&config_file, config).unwrap();
+        let plan = verify_sync(&mut keys, &options).unwrap();
+        assert!(plan.modified_on_disk);
+        let changes: Vec<_> = plan.changes.iter().map(|change| (change.kind, change.action)).collect();
+        assert_eq!(changes, [(FileKind::SyncConfig, FileAction::Overwrite)]);
+        apply_sync_plan(&plan).unwrap();
+
+        let key_file = plan.key_folder.join(&keys[1].file_name);
+        std::fs::remove_file(&key_file).unwrap();
+        let plan = verify_sync(&mut keys, &options).unwrap();
+        assert!(plan.modified_on_disk);
+        let changes: Vec<_> = plan
+            .changes
+            .iter()
+            .map(|change| (change.path.as_path(), change.action))
+            .collect();
+        assert_eq!(changes, [(key_file.as_path(), FileAction::Create)]);
+    }
+
+    #[test]
+    fn base_config_include_detection() {
+        let dir = TempDir::new();
+        dir.use_as_ssh_dir();
+        let base_config = dir.path().join("config");
+        let needs_editing = |content: &str| {
+            std::fs::write(&base_config, content).unwrap();
+            check_base_config_needs_editing().unwrap()
+        };
+        let padding = "# filler\n".repeat(25);
+
+        assert!(needs_editing("# Include config.ssh_agent_sync\n"));
+        assert!(needs_editing("Host work\n  Include config.ssh_agent_sync\n"));
+        assert!(!needs_editing(&format!("{}Include config.ssh_agent_sync\n", padding)));
+        let absolute = dir.path().join("config.ssh_agent_sync");
+        assert!(!needs_editing(&format!("include=\"{}\"\n", absolute.display())));
+        assert!(!needs_editing("Host other\n  User me\nMatch all\n  Include config.*\n"));
+    }
+
+    #[test]
+    fn manifest_round_trip_ignores_unsafe_names() {
+        let names = vec!["a.pub".to_string(), "b-cert.pub".to_string()];
+        assert_eq!(parse_manifest(&format_manifest(&names)), names);
+        let damaged = "# header\n../x.pub\n/etc/x.pub\n.hidden.pub\nsub\\x.pub\nnot-a-key\n ok.pub \n";
+        assert_eq!(parse_manifest(damaged), ["ok.pub"]);
+    }
 }
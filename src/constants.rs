@@ -15,15 +15,48 @@ pub const SSH_CONFIG_KEY_FOLDER: &str = "ssh_agent_sync";
 /// File name of the ssh-agent-sync config file.
 pub const SSH_CONFIG_FILE_NAME: &str = "config.ssh_agent_sync";
 
-/// Prefix used in the SSH config file to store the CRC of synced keys.
+/// File in the key folder listing the key files ssh-agent-sync created there.
+pub const SSH_KEY_MANIFEST_FILE_NAME: &str = ".manifest";
+
+/// File name of the user-editable overrides merged into the generated config.
+pub const SSH_OVERRIDES_FILE_NAME: &str = "ssh_agent_sync.overrides";
+
+/// Suffix of the timestamped backups of the base SSH config (e.g. `config.ssh-agent-sync-backup.20250101T120000`).
+pub const SSH_BASE_CONFIG_BACKUP_SUFFIX: &str = "ssh-agent-sync-backup";
+
+/// Number of base SSH config backups to keep.
+pub const SSH_BASE_CONFIG_BACKUPS_KEPT: usize = 5;
+
+/// Prefix used in the SSH config file to store the digest of everything the
+/// generated output is made from.
 pub const SSH_AGENT_SYNC_CRC_PREFIX: &str = "### SSH_AGENT_SYNC_CRC=";
 
+/// Prefix used in the SSH config file to store the digest of the generated
+/// config and key files, to notice edits made on disk.
+pub const SSH_AGENT_SYNC_OUTPUT_PREFIX: &str = "### SSH_AGENT_SYNC_OUTPUT=";
+
+/// File name of the persisted settings file.
+pub const SETTINGS_FILE_NAME: &str = "settings.conf";
+
+#[cfg(test)]
+thread_local! {
+    /// Stands in for the SSH directory in tests, so they never touch the real one.
+    pub(crate) static TEST_SSH_DIR: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
 /// Returns the user's SSH directory path (e.g. `$HOME/.ssh`).
 /// Returns `None` if the home directory can't be determined.
+#[cfg(not(test))]
 pub fn ssh_dir() -> Option<PathBuf> {
     dirs::home_dir().map(|d| d.join(SSH_DIR_NAME))
 }
 
+#[cfg(test)]
+pub fn ssh_dir() -> Option<PathBuf> {
+    TEST_SSH_DIR.with(|dir| dir.borrow().clone())
+}
+
 pub fn ssh_base_config_file_path() -> Option<PathBuf> {
     ssh_dir().map(|d| d.join(SSH_BASE_CONFIG_FILE_NAME))
 }
@@ -33,12 +66,30 @@ pub fn ssh_config_key_folder_path() -> Option<PathBuf> {
     ssh_dir().map(|d| d.join(SSH_CONFIG_KEY_FOLDER))
 }
 
+/// Returns the path to the key folder manifest (e.g. `$HOME/.ssh/ssh_agent_sync/.manifest`).
+pub fn ssh_key_manifest_file_path() -> Option<PathBuf> {
+    ssh_config_key_folder_path().map(|d| d.join(SSH_KEY_MANIFEST_FILE_NAME))
+}
+
 /// Returns the path to the ssh-agent-sync config file (e.g. `$HOME/.ssh/config.ssh_agent_sync`).
 pub fn ssh_config_file_path() -> Option<PathBuf> {
     ssh_dir().map(|d| d.join(SSH_CONFIG_FILE_NAME))
 }
 
+/// Returns the path to the overrides file (e.g. `$HOME/.ssh/ssh_agent_sync.overrides`).
+pub fn ssh_overrides_file_path() -> Option<PathBuf> {
+    ssh_dir().map(|d| d.join(SSH_OVERRIDES_FILE_NAME))
+}
+
 pub fn ssh_base_include_line() -> Option<String> {
     let file_path = ssh_config_file_path()?;
-    Some(format!("Include {}", file_path.display()))
+    Some(format!(
+        "Include {}",
+        crate::config::quote_config_value(&file_path.display().to_string())
+    ))
+}
+
+/// Returns the path to the settings file (e.g. `$HOME/.config/ssh-agent-sync/settings.conf`).
+pub fn settings_file_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join(PROGRAM_NAME).join(SETTINGS_FILE_NAME))
 }
@@ -0,0 +1,216 @@
+/// Lines of unchanged context shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Above this many line pairs the LCS table gets too big, and the whole file
+/// is shown as replaced instead.
+const MAX_LCS_CELLS: usize = 16 * 1024 * 1024;
+
+/// One line of a unified diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    /// `@@ -a,b +c,d @@` hunk header.
+    Hunk(String),
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A line-level edit from the LCS walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Keep(usize, usize),
+    Remove(usize),
+    Add(usize),
+}
+
+/// Compute a unified diff of `old` and `new` with [`CONTEXT_LINES`] of context.
+///
+/// Returns no lines when the texts are equal.
+pub fn unified_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let edits = line_edits(&old, &new);
+
+    // Indices of edits that change something.
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Keep(..)))
+        .map(|(idx, _)| idx)
+        .collect();
+
+    let mut out = Vec::new();
+    let mut idx = 0;
+    while idx < changes.len() {
+        // Extend the hunk while the next change is close enough to share context.
+        let start = changes[idx].saturating_sub(CONTEXT_LINES);
+        let mut last = changes[idx];
+        while idx + 1 < changes.len() && changes[idx + 1] - last <= 2 * CONTEXT_LINES + 1 {
+            idx += 1;
+            last = changes[idx];
+        }
+        let end = (last + CONTEXT_LINES + 1).min(edits.len());
+        idx += 1;
+
+        let hunk = &edits[start..end];
+        let (old_start, new_start) = edit_position(&edits[..start]);
+        let old_len = hunk.iter().filter(|e| !matches!(e, Edit::Add(_))).count();
+        let new_len = hunk.iter().filter(|e| !matches!(e, Edit::Remove(_))).count();
+        out.push(DiffLine::Hunk(format!(
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_len),
+            hunk_range(new_start, new_len)
+        )));
+        for edit in hunk {
+            out.push(match *edit {
+                Edit::Keep(i, _) => DiffLine::Context(old[i].to_string()),
+                Edit::Remove(i) => DiffLine::Removed(old[i].to_string()),
+                Edit::Add(j) => DiffLine::Added(new[j].to_string()),
+            });
+        }
+    }
+    out
+}
+
+/// Lines of old and new consumed by `edits`.
+fn edit_position(edits: &[Edit]) -> (usize, usize) {
+    let old = edits.iter().filter(|e| !matches!(e, Edit::Add(_))).count();
+    let new = edits.iter().filter(|e| !matches!(e, Edit::Remove(_))).count();
+    (old, new)
+}
+
+/// Format a hunk range the way `diff -u` does: 1-based start, and an empty
+/// range starts at the line before.
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Shortest edit script between `old` and `new` via a longest common subsequence.
+fn line_edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    // Common prefix and suffix don't need the table.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut edits: Vec<Edit> = (0..prefix).map(|i| Edit::Keep(i, i)).collect();
+
+    let (n, m) = (old_mid.len(), new_mid.len());
+    if (n + 1) * (m + 1) > MAX_LCS_CELLS {
+        edits.extend((0..n).map(|i| Edit::Remove(prefix + i)));
+        edits.extend((0..m).map(|j| Edit::Add(prefix + j)));
+    } else {
+        // lcs[i][j] = length of the LCS of old_mid[i..] and new_mid[j..].
+        let width = m + 1;
+        let mut lcs = vec![0u32; (n + 1) * width];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_mid[i] == new_mid[j] {
+                edits.push(Edit::Keep(prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if j < m && (i == n || lcs[i * width + j + 1] > lcs[(i + 1) * width + j]) {
+                // Strictly better only, so removals come first on ties, like `diff -u`.
+                edits.push(Edit::Add(prefix + j));
+                j += 1;
+            } else {
+                edits.push(Edit::Remove(prefix + i));
+                i += 1;
+            }
+        }
+    }
+
+    let (old_tail, new_tail) = (old.len() - suffix, new.len() - suffix);
+    edits.extend((0..suffix).map(|k| Edit::Keep(old_tail + k, new_tail + k)));
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(old: &str, new: &str) -> Vec<String> {
+        unified_diff(old, new)
+            .into_iter()
+            .map(|line| match line {
+                DiffLine::Hunk(header) => header,
+                DiffLine::Context(line) => format!(" {}", line),
+                DiffLine::Removed(line) => format!("-{}", line),
+                DiffLine::Added(line) => format!("+{}", line),
+            })
+            .collect()
+    }
+
+    fn hunks(old: &str, new: &str) -> Vec<String> {
+        render(old, new).into_iter().filter(|line| line.starts_with("@@")).collect()
+    }
+
+    /// Lines numbered `range`, with the lines in `replace` swapped for new text.
+    fn numbered(range: std::ops::Range<usize>, replace: &[(usize, &str)]) -> String {
+        range
+            .map(|i| match replace.iter().find(|(line, _)| *line == i) {
+                Some((_, text)) => format!("{}\n", text),
+                None => format!("{}\n", i),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn equal_texts_have_no_diff() {
+        assert!(unified_diff("a\nb\n", "a\nb\n").is_empty());
+        assert!(unified_diff("", "").is_empty());
+    }
+
+    #[test]
+    fn new_and_deleted_files() {
+        assert_eq!(render("", "a\nb\n"), ["@@ -0,0 +1,2 @@", "+a", "+b"]);
+        assert_eq!(render("a\n", ""), ["@@ -1 +0,0 @@", "-a"]);
+    }
+
+    #[test]
+    fn change_with_context() {
+        let old = numbered(1..10, &[]);
+        let new = numbered(1..10, &[(5, "five")]);
+        assert_eq!(
+            render(&old, &new),
+            ["@@ -2,7 +2,7 @@", " 2", " 3", " 4", "-5", "+five", " 6", " 7", " 8"]
+        );
+    }
+
+    #[test]
+    fn distant_changes_get_separate_hunks() {
+        let old = numbered(1..30, &[]);
+        let new = numbered(1..30, &[(2, "two"), (25, "twenty-five")]);
+        assert_eq!(hunks(&old, &new), ["@@ -1,5 +1,5 @@", "@@ -22,7 +22,7 @@"]);
+
+        // Changes within twice the context share one hunk.
+        let new = numbered(1..30, &[(2, "two"), (9, "nine")]);
+        assert_eq!(hunks(&old, &new), ["@@ -1,12 +1,12 @@"]);
+    }
+
+    #[test]
+    fn insertion_keeps_common_lines() {
+        assert_eq!(
+            render("a\nc\n", "a\nb\nc\n"),
+            ["@@ -1,2 +1,3 @@", " a", "+b", " c"]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replace the contents of `path` without ever leaving it half written.
+///
+/// The data goes to a temporary file in the same directory, is flushed to
+/// disk and then renamed over `path`, so a crash or full disk leaves either
+/// the old or the new file. An existing file keeps its permissions (and, on
+/// unix, its owner); a new file is created with `new_mode`. If `path` is a
+/// symlink, the file it points to is replaced and the link kept.
+pub fn write_atomic(path: &Path, contents: &[u8], new_mode: u32) -> io::Result<()> {
+    let path = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(e),
+    };
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let existing = fs::metadata(&path).ok();
+    let temp_path = temp_path_for(&path);
+
+    // Left over from a crashed run with the same pid.
+    let _ = fs::remove_file(&temp_path);
+
+    let result = (|| {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+            options.mode(existing.as_ref().map_or(new_mode, |meta| meta.mode() & 0o7777));
+        }
+        #[cfg(not(unix))]
+        let _ = new_mode;
+
+        let mut file = options.open(&temp_path)?;
+        file.write_all(contents)?;
+        if let Some(ref meta) = existing {
+            file.set_permissions(meta.permissions())?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                std::os::unix::fs::fchown(&file, Some(meta.uid()), Some(meta.gid()))?;
+            }
+        }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp-{}", file_name, std::process::id()))
+}
+
+/// Copy `path` to a timestamped backup next to it, keeping only the newest `keep`.
+///
+/// Backups are named `<file name>.<suffix>.<YYYYMMDDTHHMMSS>`; only files
+/// with that prefix are ever rotated out. Returns the backup path, or `None`
+/// if `path` doesn't exist.
+pub fn backup_with_rotation(path: &Path, suffix: &str, keep: usize) -> io::Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let prefix = format!("{}.{}.", file_name, suffix);
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let stamp = crate::pubkey::format_timestamp(secs).replace(['-', ':'], "");
+    let backup_path = path.with_file_name(format!("{}{}", prefix, stamp));
+
+    // A backup from this same second already holds the pre-edit contents.
+    if !backup_path.exists() {
+        let contents = fs::read(path)?;
+        write_atomic(&backup_path, &contents, 0o600)?;
+    }
+
+    let dir = backup_path.parent().unwrap_or(Path::new("."));
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path())
+        .collect();
+    // Timestamps sort lexicographically, oldest first.
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for old in &backups[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(Some(backup_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Names of the files in `dir`, sorted.
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn writes_new_and_existing_files() {
+        let dir = TempDir::new();
+        let path = dir.path().join("config");
+        write_atomic(&path, b"one", 0o600).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"one");
+        write_atomic(&path, b"two", 0o600).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"two");
+        assert_eq!(file_names(dir.path()), ["config"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_mode_of_existing_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new();
+        let path = dir.path().join("config");
+        write_atomic(&path, b"one", 0o600).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"two", 0o600).unwrap();
+        assert_eq!(mode(&path), 0o640);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks() {
+        let dir = TempDir::new();
+        let target = dir.path().join("real_config");
+        let link = dir.path().join("config");
+        fs::write(&target, b"old").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        write_atomic(&link, b"new", 0o600).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(file_names(dir.path()), ["config", "real_config"]);
+    }
+
+    #[test]
+    fn removes_the_temp_file_when_the_write_fails() {
+        let dir = TempDir::new();
+        // Renaming a file over a directory fails after the temp file is written.
+        let path = dir.path().join("config");
+        fs::create_dir(&path).unwrap();
+        assert!(write_atomic(&path, b"data", 0o600).is_err());
+        assert_eq!(file_names(dir.path()), ["config"]);
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_backups() {
+        let dir = TempDir::new();
+        let path = dir.path().join("config");
+        fs::write(&path, b"current").unwrap();
+        for day in 1..=6 {
+            fs::write(dir.path().join(format!("config.bak.2020010{}T000000", day)), b"old").unwrap();
+        }
+        fs::write(dir.path().join("config.other"), b"unrelated").unwrap();
+
+        let backup = backup_with_rotation(&path, "bak", 5).unwrap().unwrap();
+        assert_eq!(fs::read(&backup).unwrap(), b"current");
+        let names = file_names(dir.path());
+        let backups: Vec<_> = names.iter().filter(|name| name.starts_with("config.bak.")).collect();
+        assert_eq!(backups.len(), 5);
+        assert_eq!(backups[0], "config.bak.20200103T000000");
+        assert!(names.contains(&"config.other".to_string()));
+        assert_eq!(backup_with_rotation(&dir.path().join("missing"), "bak", 5).unwrap(), None);
+    }
+}
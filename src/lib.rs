@@ -1,9 +1,33 @@
 pub mod constants;
 pub mod types;
 pub mod agent;
+pub mod agent_client;
 pub mod config;
+pub mod diff;
+pub mod fsutil;
+pub mod keywords;
+pub mod overrides;
+pub mod pattern;
+pub mod policy;
+pub mod pubkey;
+pub mod settings;
+pub mod ssh_config;
+pub mod template;
+#[cfg(test)]
+mod testutil;
 
 pub use constants::*;
 pub use types::*;
 pub use agent::*;
-pub use config::*;
\ No newline at end of file
+pub use agent_client::*;
+pub use config::*;
+pub use diff::*;
+pub use fsutil::*;
+pub use keywords::*;
+pub use overrides::*;
+pub use pattern::*;
+pub use policy::*;
+pub use pubkey::*;
+pub use settings::*;
+pub use ssh_config::*;
+pub use template::*;
@@ -0,0 +1,202 @@
+use crate::keywords::is_ssh_config_keyword;
+use crate::ssh_config::{Scope, SshConfig};
+use std::path::Path;
+
+/// Keywords the generated blocks depend on, which overrides can't replace.
+const FIXED_KEYWORDS: &[&str] = &[
+    "host",
+    "match",
+    "include",
+    "hostname",
+    "identityfile",
+    "certificatefile",
+    "identitiesonly",
+];
+
+/// A single `Keyword value` line from the overrides file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    pub keyword: String,
+    pub value: String,
+}
+
+/// Directives for the hosts or nicknames on one `Host` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverrideSection {
+    pub hosts: Vec<String>,
+    pub directives: Vec<Directive>,
+}
+
+/// User directives merged into the generated `Host` blocks.
+///
+/// Read from [`ssh_overrides_file_path`](crate::constants::ssh_overrides_file_path),
+/// which uses ssh_config syntax: each `Host` line names hosts or nicknames
+/// exactly as they appear in the generated config, followed by the
+/// directives to add to their blocks. A directive replaces any generated
+/// line with the same keyword.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub sections: Vec<OverrideSection>,
+}
+
+impl Overrides {
+    /// Load the overrides file. A missing file yields no overrides.
+    pub fn load() -> Result<Self, String> {
+        match crate::constants::ssh_overrides_file_path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Load overrides from `path`. A missing file yields no overrides.
+    pub fn load_from(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read overrides file {}: {}", path.display(), e)
+        })?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self, String> {
+        let config = SshConfig::parse(content)?;
+        let mut overrides = Self::default();
+        for section in &config.sections {
+            let hosts = match section.scope {
+                Scope::Global => {
+                    if let Some(line) = section.directives.first() {
+                        return Err(format!(
+                            "line {}: `{}` before any `Host` line",
+                            line.line_number, line.keyword
+                        ));
+                    }
+                    continue;
+                }
+                Scope::Host(ref hosts) => hosts.clone(),
+                Scope::Match(_) => {
+                    return Err(format!("line {}: `Match` isn't supported, use `Host`", section.line_number));
+                }
+            };
+            let mut directives = Vec::new();
+            for line in &section.directives {
+                if !is_ssh_config_keyword(&line.keyword) {
+                    return Err(format!(
+                        "line {}: unknown ssh_config keyword `{}`",
+                        line.line_number, line.keyword
+                    ));
+                }
+                if FIXED_KEYWORDS.contains(&line.keyword.to_ascii_lowercase().as_str()) {
+                    return Err(format!("line {}: `{}` can't be overridden", line.line_number, line.keyword));
+                }
+                if line.value.contains('"') {
+                    return Err(format!("line {}: quotes aren't supported in values", line.line_number));
+                }
+                directives.push(Directive {
+                    keyword: line.keyword.clone(),
+                    value: line.value.clone(),
+                });
+            }
+            overrides.sections.push(OverrideSection { hosts, directives });
+        }
+        Ok(overrides)
+    }
+
+    /// Indices of the sections naming any of `hosts`, in file order.
+    pub fn sections_for(&self, hosts: &[&str]) -> Vec<usize> {
+        self.sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.hosts.iter().any(|host| hosts.contains(&host.as_str())))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Directives of the given sections. Like ssh, the first section to set a
+    /// keyword wins; a keyword repeated within that section keeps every line.
+    pub fn directives(&self, sections: &[usize]) -> Vec<&Directive> {
+        let mut directives = Vec::new();
+        let mut set_earlier: Vec<String> = Vec::new();
+        for &idx in sections {
+            let section = &self.sections[idx];
+            directives.extend(
+                section
+                    .directives
+                    .iter()
+                    .filter(|d| !set_earlier.contains(&d.keyword.to_ascii_lowercase())),
+            );
+            set_earlier.extend(section.directives.iter().map(|d| d.keyword.to_ascii_lowercase()));
+        }
+        directives
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections() {
+        let overrides = Overrides::parse(
+            "# comment\nHost a b\n  Port 2200\n  ForwardAgent=yes\n\nHost c\n  user admin\n",
+        )
+        .unwrap();
+        assert_eq!(overrides.sections.len(), 2);
+        assert_eq!(overrides.sections[0].hosts, ["a", "b"]);
+        assert_eq!(
+            overrides.sections[0].directives[1],
+            Directive {
+                keyword: "ForwardAgent".to_string(),
+                value: "yes".to_string(),
+            }
+        );
+        assert_eq!(overrides.sections_for(&["c", "x"]), [1]);
+    }
+
+    #[test]
+    fn rejects_fixed_keywords() {
+        for keyword in ["HostName", "IdentityFile", "CertificateFile", "IdentitiesOnly", "Include"] {
+            let error = Overrides::parse(&format!("Host a\n  {} x\n", keyword)).unwrap_err();
+            assert_eq!(error, format!("line 2: `{}` can't be overridden", keyword));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keywords() {
+        assert_eq!(
+            Overrides::parse("Host a\n  Colour blue\n").unwrap_err(),
+            "line 2: unknown ssh_config keyword `Colour`"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(
+            Overrides::parse("Port 22\n").unwrap_err(),
+            "line 1: `Port` before any `Host` line"
+        );
+        assert_eq!(Overrides::parse("Host a\n  Port\n").unwrap_err(), "line 2: `Port` has no value");
+        assert_eq!(
+            Overrides::parse("Match all\n  Port 22\n").unwrap_err(),
+            "line 1: `Match` isn't supported, use `Host`"
+        );
+        assert_eq!(
+            Overrides::parse("Host a\n  RemoteCommand \"ls\"\n").unwrap_err(),
+            "line 2: quotes aren't supported in values"
+        );
+    }
+
+    #[test]
+    fn first_section_wins() {
+        let overrides =
+            Overrides::parse("Host a\n  Port 1\n  SendEnv A\n  SendEnv B\nHost b\n  Port 2\n  User u\n")
+                .unwrap();
+        let directives: Vec<_> = overrides
+            .directives(&[0, 1])
+            .iter()
+            .map(|d| format!("{} {}", d.keyword, d.value))
+            .collect();
+        assert_eq!(directives, ["Port 1", "SendEnv A", "SendEnv B", "User u"]);
+    }
+}
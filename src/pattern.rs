@@ -0,0 +1,102 @@
+/// Match `text` against a wildcard `pattern` where `*` matches any run of
+/// characters (including none) and `?` matches exactly one character.
+///
+/// This is the matching used by ssh_config `Host` patterns.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was matched at,
+    // so we can backtrack and let it swallow one more character.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `host` is an ssh_config pattern rather than a literal host name.
+pub fn is_host_pattern(host: &str) -> bool {
+    host.contains(['*', '?'])
+}
+
+/// How specific a host pattern is: the number of characters that aren't
+/// wildcards. Used to write more specific `Host` blocks first, since ssh
+/// takes the first value it finds for each option.
+pub fn pattern_specificity(pattern: &str) -> usize {
+    pattern.chars().filter(|c| !matches!(c, '*' | '?')).count()
+}
+
+/// Match `path` against an `Include` pattern the way glob(3) does: component
+/// by component, so wildcards never match a `/`, and a wildcard doesn't match
+/// the leading `.` of a hidden file.
+pub fn path_glob_match(pattern: &std::path::Path, path: &std::path::Path) -> bool {
+    let pattern: Vec<_> = pattern.components().collect();
+    let path: Vec<_> = path.components().collect();
+    pattern.len() == path.len()
+        && pattern.iter().zip(&path).all(|(pattern, component)| {
+            let pattern = pattern.as_os_str().to_string_lossy();
+            let component = component.as_os_str().to_string_lossy();
+            if component.starts_with('.') && !pattern.starts_with('.') {
+                return false;
+            }
+            wildcard_match(&pattern, &component)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match("host?", "host1"));
+        assert!(!wildcard_match("host?", "host"));
+        assert!(!wildcard_match("host?", "host12"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("a*b*c", "aXbYcZ"));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("exact", "Exact"));
+    }
+
+    #[test]
+    fn specificity() {
+        assert!(is_host_pattern("*.example"));
+        assert!(is_host_pattern("db?"));
+        assert!(!is_host_pattern("db.example"));
+        assert_eq!(pattern_specificity("*.prod.example"), 13);
+        assert!(pattern_specificity("*.prod.example") > pattern_specificity("*.example"));
+    }
+
+    #[test]
+    fn path_globs_stay_within_components() {
+        let target = Path::new("/home/me/.ssh/config.ssh_agent_sync");
+        assert!(path_glob_match(Path::new("/home/me/.ssh/config.*"), target));
+        assert!(path_glob_match(Path::new("/home/*/.ssh/*"), target));
+        assert!(path_glob_match(target, target));
+        // `*` doesn't cross `/`, and doesn't match a leading `.`.
+        assert!(!path_glob_match(Path::new("/home/*"), target));
+        assert!(!path_glob_match(Path::new("/home/me/*/config.ssh_agent_sync"), target));
+        assert!(!path_glob_match(Path::new("/home/me/.ssh/*/config.ssh_agent_sync"), target));
+        assert!(!path_glob_match(Path::new("/home/me/.ssh/config"), target));
+    }
+}
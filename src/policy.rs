@@ -0,0 +1,293 @@
+use crate::pattern::wildcard_match;
+use crate::types::SshKey;
+
+/// What to do with keys that break the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// Leave the key out of the generated config.
+    #[default]
+    Exclude,
+    /// Sync the key anyway and only report the violation.
+    Warn,
+}
+
+impl std::str::FromStr for PolicyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "exclude" => Ok(PolicyAction::Exclude),
+            "warn" => Ok(PolicyAction::Warn),
+            _ => Err(format!("expected exclude or warn, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PolicyAction::Exclude => "exclude",
+            PolicyAction::Warn => "warn",
+        })
+    }
+}
+
+/// Rules deciding which agent keys may be written to the ssh config.
+///
+/// An empty policy allows every key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Smallest RSA modulus allowed, in bits.
+    pub min_rsa_bits: Option<u32>,
+    /// Key algorithms allowed, e.g. `ssh-ed25519`. Empty allows all.
+    /// Certificates are checked against the algorithm of the key they certify.
+    pub allowed_algorithms: Vec<String>,
+    /// Wildcard patterns (`*`, `?`) matched against the key comment; matching keys are denied.
+    pub denied_comments: Vec<String>,
+    pub action: PolicyAction,
+}
+
+/// Why a key broke the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyReason {
+    AlgorithmNotAllowed(String),
+    RsaTooSmall { bits: u32, min_bits: u32 },
+    CommentDenied(String),
+    /// The key blob couldn't be parsed to check its size.
+    Unparseable,
+}
+
+impl std::fmt::Display for PolicyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyReason::AlgorithmNotAllowed(algorithm) => {
+                write!(f, "algorithm {} is not allowed", algorithm)
+            }
+            PolicyReason::RsaTooSmall { bits, min_bits } => {
+                write!(f, "RSA key is {} bits, minimum is {}", bits, min_bits)
+            }
+            PolicyReason::CommentDenied(pattern) => {
+                write!(f, "comment matches denied pattern {:?}", pattern)
+            }
+            PolicyReason::Unparseable => write!(f, "key could not be parsed"),
+        }
+    }
+}
+
+/// A key that broke the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation {
+    pub key: SshKey,
+    pub reason: PolicyReason,
+    /// Whether the key was left out of the config (otherwise it was only reported).
+    pub excluded: bool,
+}
+
+impl KeyPolicy {
+    /// Whether the policy has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.min_rsa_bits.is_none()
+            && self.allowed_algorithms.is_empty()
+            && self.denied_comments.is_empty()
+    }
+
+    /// Check a single key, returning the first rule it breaks.
+    pub fn check(&self, key: &SshKey) -> Option<PolicyReason> {
+        if let Some(pattern) = self
+            .denied_comments
+            .iter()
+            .find(|pattern| wildcard_match(pattern, &key.comment))
+        {
+            return Some(PolicyReason::CommentDenied(pattern.clone()));
+        }
+
+        if self.allowed_algorithms.is_empty() && self.min_rsa_bits.is_none() {
+            return None;
+        }
+        let Ok(public_key) = key.public_key() else {
+            return Some(PolicyReason::Unparseable);
+        };
+        // Judge certificates by the key they certify.
+        let public_key = match public_key.certificate() {
+            Some(certificate) => certificate.key.clone(),
+            None => public_key,
+        };
+
+        if !self.allowed_algorithms.is_empty()
+            && !self.allowed_algorithms.contains(&public_key.algorithm)
+        {
+            return Some(PolicyReason::AlgorithmNotAllowed(public_key.algorithm));
+        }
+        if let Some(min_bits) = self.min_rsa_bits
+            && public_key.algorithm == "ssh-rsa"
+        {
+            let bits = public_key.bits().unwrap_or_default();
+            if bits < min_bits {
+                return Some(PolicyReason::RsaTooSmall { bits, min_bits });
+            }
+        }
+        None
+    }
+
+    /// Apply the policy to `keys`.
+    ///
+    /// Returns the violations; with [`PolicyAction::Exclude`] the offending
+    /// keys are removed from `keys`.
+    pub fn apply(&self, keys: &mut Vec<SshKey>) -> Vec<PolicyViolation> {
+        let excluded = self.action == PolicyAction::Exclude;
+        let mut violations = Vec::new();
+        keys.retain(|key| match self.check(key) {
+            Some(reason) => {
+                // A key with several targets is reported once.
+                let reported = violations.iter().any(|violation: &PolicyViolation| {
+                    violation.key.key == key.key && violation.key.comment == key.comment
+                });
+                if !reported {
+                    violations.push(PolicyViolation {
+                        key: key.clone(),
+                        reason,
+                        excluded,
+                    });
+                }
+                !excluded
+            }
+            None => true,
+        });
+        violations
+    }
+}
+
+/// Print policy violations, with the reason for each.
+pub fn print_policy_violations(violations: &[PolicyViolation]) {
+    if violations.is_empty() {
+        return;
+    }
+    println!("policy violations:");
+    for violation in violations {
+        println!(
+            "{:?}: {} ({})",
+            violation.key.comment,
+            violation.reason,
+            if violation.excluded { "excluded" } else { "warning only" }
+        );
+    }
+    println!("total violations: {}", violations.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{FakeAgent, get_ssh_keys_from};
+    use crate::testutil::{ed25519_blob, put_string};
+
+    /// An RSA public key blob with a modulus of `bits` bits.
+    fn rsa_blob(bits: usize) -> Vec<u8> {
+        let mut blob = Vec::new();
+        put_string(&mut blob, b"ssh-rsa");
+        put_string(&mut blob, &[0x01, 0x00, 0x01]);
+        let mut modulus = vec![0x00, 0xc0];
+        modulus.extend_from_slice(&vec![0; bits / 8 - 1]);
+        put_string(&mut blob, &modulus);
+        blob
+    }
+
+    fn keys(identities: Vec<(Vec<u8>, &str)>) -> Vec<SshKey> {
+        let agent = identities
+            .into_iter()
+            .fold(FakeAgent::new(), |agent, (blob, comment)| {
+                agent.with_identity(blob, comment)
+            });
+        get_ssh_keys_from(&agent).unwrap().keys
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = KeyPolicy::default();
+        assert!(policy.is_empty());
+        let mut keys = keys(vec![(rsa_blob(1024), "old@host"), (ed25519_blob(1), "new@host")]);
+        assert!(policy.apply(&mut keys).is_empty());
+        assert_eq!(keys.len(), 2);
+    }
+
+    #[test]
+    fn min_rsa_bits() {
+        let policy = KeyPolicy {
+            min_rsa_bits: Some(2048),
+            ..KeyPolicy::default()
+        };
+        let keys = keys(vec![
+            (rsa_blob(1024), "old@host"),
+            (rsa_blob(2048), "ok@host"),
+            (ed25519_blob(1), "new@host"),
+        ]);
+        assert_eq!(
+            policy.check(&keys[0]),
+            Some(PolicyReason::RsaTooSmall {
+                bits: 1024,
+                min_bits: 2048
+            })
+        );
+        assert_eq!(policy.check(&keys[1]), None);
+        assert_eq!(policy.check(&keys[2]), None);
+    }
+
+    #[test]
+    fn allowed_algorithms() {
+        let policy = KeyPolicy {
+            allowed_algorithms: vec!["ssh-ed25519".to_string()],
+            ..KeyPolicy::default()
+        };
+        let keys = keys(vec![(rsa_blob(4096), "rsa@host"), (ed25519_blob(1), "new@host")]);
+        assert_eq!(
+            policy.check(&keys[0]),
+            Some(PolicyReason::AlgorithmNotAllowed("ssh-rsa".to_string()))
+        );
+        assert_eq!(policy.check(&keys[1]), None);
+    }
+
+    #[test]
+    fn denied_comments() {
+        let policy = KeyPolicy {
+            denied_comments: vec!["*@old.example".to_string(), "temp?@*".to_string()],
+            ..KeyPolicy::default()
+        };
+        let keys = keys(vec![
+            (ed25519_blob(1), "me@old.example"),
+            (ed25519_blob(2), "temp1@host"),
+            (ed25519_blob(3), "temp12@host"),
+        ]);
+        assert_eq!(
+            policy.check(&keys[0]),
+            Some(PolicyReason::CommentDenied("*@old.example".to_string()))
+        );
+        assert_eq!(
+            policy.check(&keys[1]),
+            Some(PolicyReason::CommentDenied("temp?@*".to_string()))
+        );
+        assert_eq!(policy.check(&keys[2]), None);
+    }
+
+    #[test]
+    fn exclude_removes_keys_and_warn_keeps_them() {
+        let mut policy = KeyPolicy {
+            min_rsa_bits: Some(2048),
+            ..KeyPolicy::default()
+        };
+        let identities = vec![(rsa_blob(1024), "old@host"), (ed25519_blob(1), "new@host")];
+
+        let mut excluded = keys(identities.clone());
+        let violations = policy.apply(&mut excluded);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].excluded);
+        assert_eq!(violations[0].key.comment, "old@host");
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].comment, "new@host");
+
+        policy.action = PolicyAction::Warn;
+        let mut warned = keys(identities);
+        let violations = policy.apply(&mut warned);
+        assert_eq!(violations.len(), 1);
+        assert!(!violations[0].excluded);
+        assert_eq!(warned.len(), 2);
+    }
+}
@@ -0,0 +1,675 @@
+use std::fmt;
+
+/// Errors returned when a public key blob doesn't follow the SSH wire format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyParseError {
+    /// The blob ended in the middle of a field.
+    Truncated,
+    /// The blob has bytes left over after the last field of the key.
+    TrailingData(usize),
+    /// A string field that must be text isn't valid UTF-8.
+    InvalidUtf8,
+    /// The curve named inside an ECDSA key doesn't match its algorithm.
+    CurveMismatch { expected: String, found: String },
+    /// An ECDSA key uses a curve we don't know.
+    UnknownCurve(String),
+    /// A key field has the wrong length or encoding for its algorithm.
+    InvalidKey(String),
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyParseError::Truncated => write!(f, "key data is truncated"),
+            KeyParseError::TrailingData(n) => write!(f, "{} trailing bytes after key data", n),
+            KeyParseError::InvalidUtf8 => write!(f, "key data contains invalid UTF-8 text"),
+            KeyParseError::CurveMismatch { expected, found } => {
+                write!(f, "expected curve {} but key uses {}", expected, found)
+            }
+            KeyParseError::UnknownCurve(curve) => write!(f, "unknown ECDSA curve {}", curve),
+            KeyParseError::InvalidKey(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for KeyParseError {}
+
+/// NIST curves used by ECDSA keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcdsaCurve {
+    NistP256,
+    NistP384,
+    NistP521,
+}
+
+impl EcdsaCurve {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nistp256" => Some(EcdsaCurve::NistP256),
+            "nistp384" => Some(EcdsaCurve::NistP384),
+            "nistp521" => Some(EcdsaCurve::NistP521),
+            _ => None,
+        }
+    }
+
+    /// The curve identifier used in key types, e.g. `nistp256`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EcdsaCurve::NistP256 => "nistp256",
+            EcdsaCurve::NistP384 => "nistp384",
+            EcdsaCurve::NistP521 => "nistp521",
+        }
+    }
+
+    /// Size of the curve in bits.
+    pub fn bits(&self) -> u32 {
+        match self {
+            EcdsaCurve::NistP256 => 256,
+            EcdsaCurve::NistP384 => 384,
+            EcdsaCurve::NistP521 => 521,
+        }
+    }
+
+    /// Length of one coordinate of a point on this curve.
+    fn coordinate_len(&self) -> usize {
+        self.bits().div_ceil(8) as usize
+    }
+}
+
+impl fmt::Display for EcdsaCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Algorithm specific contents of a public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyData {
+    Rsa {
+        exponent: Vec<u8>,
+        modulus: Vec<u8>,
+    },
+    Dsa {
+        p: Vec<u8>,
+        q: Vec<u8>,
+        g: Vec<u8>,
+        y: Vec<u8>,
+    },
+    Ecdsa {
+        curve: EcdsaCurve,
+        point: Vec<u8>,
+    },
+    Ed25519 {
+        key: Vec<u8>,
+    },
+    /// ECDSA key held on a FIDO security key.
+    SkEcdsa {
+        curve: EcdsaCurve,
+        point: Vec<u8>,
+        application: String,
+    },
+    /// Ed25519 key held on a FIDO security key.
+    SkEd25519 {
+        key: Vec<u8>,
+        application: String,
+    },
+    /// An OpenSSH certificate.
+    Certificate(Box<Certificate>),
+    /// An algorithm we don't parse; only its type string was read.
+    Other,
+}
+
+/// A parsed SSH public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    /// Key type string from the blob, e.g. `ssh-ed25519`.
+    pub algorithm: String,
+    pub data: KeyData,
+}
+
+impl PublicKey {
+    /// Parse a public key blob in SSH wire format.
+    ///
+    /// The whole blob is validated for the algorithms we know; truncated blobs
+    /// and blobs with trailing bytes are rejected. Blobs of other algorithms
+    /// are accepted with [`KeyData::Other`] so new key types keep syncing.
+    pub fn parse(blob: &[u8]) -> Result<Self, KeyParseError> {
+        let mut reader = WireReader::new(blob);
+        let algorithm = reader.read_str()?.to_string();
+
+        if let Some(base) = certificate_base_algorithm(&algorithm) {
+            let certificate = read_certificate(&mut reader, &base)?;
+            reader.finish()?;
+            return Ok(PublicKey {
+                algorithm,
+                data: KeyData::Certificate(Box::new(certificate)),
+            });
+        }
+
+        let data = read_key_data(&mut reader, &algorithm)?;
+        // Unknown algorithms can't be validated, so their fields are left unread.
+        if data != KeyData::Other {
+            reader.finish()?;
+        }
+        Ok(PublicKey { algorithm, data })
+    }
+
+    /// The certificate details, for `*-cert-v01@openssh.com` keys.
+    pub fn certificate(&self) -> Option<&Certificate> {
+        match self.data {
+            KeyData::Certificate(ref certificate) => Some(certificate),
+            _ => None,
+        }
+    }
+
+    /// Key size in bits: the RSA modulus or DSA `p` size, the ECDSA curve size,
+    /// or 256 for Ed25519. Certificates report the size of their key. `None`
+    /// for algorithms we don't parse.
+    pub fn bits(&self) -> Option<u32> {
+        match self.data {
+            KeyData::Certificate(ref certificate) => certificate.key.bits(),
+            KeyData::Rsa { ref modulus, .. } => Some(mpint_bits(modulus)),
+            KeyData::Dsa { ref p, .. } => Some(mpint_bits(p)),
+            KeyData::Ecdsa { curve, .. } | KeyData::SkEcdsa { curve, .. } => Some(curve.bits()),
+            KeyData::Ed25519 { .. } | KeyData::SkEd25519 { .. } => Some(256),
+            KeyData::Other => None,
+        }
+    }
+
+    /// The ECDSA curve, for ECDSA keys and certificates.
+    pub fn curve(&self) -> Option<EcdsaCurve> {
+        match self.data {
+            KeyData::Certificate(ref certificate) => certificate.key.curve(),
+            KeyData::Ecdsa { curve, .. } | KeyData::SkEcdsa { curve, .. } => Some(curve),
+            _ => None,
+        }
+    }
+
+    /// The FIDO application string (usually `ssh:`), for security keys and their certificates.
+    pub fn application(&self) -> Option<&str> {
+        match self.data {
+            KeyData::Certificate(ref certificate) => certificate.key.application(),
+            KeyData::SkEcdsa {
+                ref application, ..
+            }
+            | KeyData::SkEd25519 {
+                ref application, ..
+            } => Some(application),
+            _ => None,
+        }
+    }
+
+    /// Short description in the style of `ssh-keygen -l`, e.g. `RSA 3072` or `ECDSA-SK nistp256 ssh:`.
+    pub fn summary(&self) -> String {
+        match self.data {
+            KeyData::Rsa { .. } => format!("RSA {}", self.bits().unwrap_or_default()),
+            KeyData::Dsa { .. } => format!("DSA {}", self.bits().unwrap_or_default()),
+            KeyData::Ecdsa { curve, .. } => format!("ECDSA {}", curve),
+            KeyData::Ed25519 { .. } => "ED25519".to_string(),
+            KeyData::SkEcdsa {
+                curve,
+                ref application,
+                ..
+            } => format!("ECDSA-SK {} {}", curve, application),
+            KeyData::SkEd25519 {
+                ref application, ..
+            } => format!("ED25519-SK {}", application),
+            KeyData::Certificate(ref certificate) => format!(
+                "{} {} certificate, id {:?}, principals [{}], {}",
+                certificate.key.summary(),
+                certificate.cert_type,
+                certificate.key_id,
+                certificate.principals.join(","),
+                certificate.validity()
+            ),
+            KeyData::Other => self.algorithm.clone(),
+        }
+    }
+}
+
+/// Read the algorithm specific public key fields that follow the type string.
+fn read_key_data(reader: &mut WireReader, algorithm: &str) -> Result<KeyData, KeyParseError> {
+    let data = match algorithm {
+        "ssh-rsa" => {
+            let exponent = reader.read_mpint()?;
+            let modulus = reader.read_mpint()?;
+            if modulus.is_empty() {
+                return Err(KeyParseError::InvalidKey("RSA modulus is empty".to_string()));
+            }
+            KeyData::Rsa { exponent, modulus }
+        }
+        "ssh-dss" => KeyData::Dsa {
+            p: reader.read_mpint()?,
+            q: reader.read_mpint()?,
+            g: reader.read_mpint()?,
+            y: reader.read_mpint()?,
+        },
+        "ssh-ed25519" => KeyData::Ed25519 {
+            key: read_ed25519_key(reader)?,
+        },
+        "sk-ssh-ed25519@openssh.com" => {
+            let key = read_ed25519_key(reader)?;
+            let application = reader.read_str()?.to_string();
+            KeyData::SkEd25519 { key, application }
+        }
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            let (curve, point) = read_ecdsa_point(reader, "nistp256")?;
+            let application = reader.read_str()?.to_string();
+            KeyData::SkEcdsa {
+                curve,
+                point,
+                application,
+            }
+        }
+        other => match other.strip_prefix("ecdsa-sha2-") {
+            Some(curve_name) => {
+                let (curve, point) = read_ecdsa_point(reader, curve_name)?;
+                KeyData::Ecdsa { curve, point }
+            }
+            None => KeyData::Other,
+        },
+    };
+    Ok(data)
+}
+
+/// Suffix of OpenSSH certificate key types.
+const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+/// The plain key algorithm certified by a certificate type, e.g.
+/// `ssh-ed25519-cert-v01@openssh.com` -> `ssh-ed25519` and
+/// `sk-ssh-ed25519-cert-v01@openssh.com` -> `sk-ssh-ed25519@openssh.com`.
+fn certificate_base_algorithm(algorithm: &str) -> Option<String> {
+    let base = algorithm.strip_suffix(CERT_SUFFIX)?;
+    if base.starts_with("sk-") {
+        Some(format!("{}@openssh.com", base))
+    } else {
+        Some(base.to_string())
+    }
+}
+
+/// Whether a certificate authenticates a user or a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertType {
+    User,
+    Host,
+}
+
+impl fmt::Display for CertType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CertType::User => "user",
+            CertType::Host => "host",
+        })
+    }
+}
+
+/// An OpenSSH certificate (`PROTOCOL.certkeys`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    /// The certified public key.
+    pub key: PublicKey,
+    /// Wire blob of the certified public key, as the agent reports the plain key.
+    pub key_blob: Vec<u8>,
+    pub serial: u64,
+    pub cert_type: CertType,
+    pub key_id: String,
+    /// Principals the certificate is valid for; empty means any principal.
+    pub principals: Vec<String>,
+    /// Start of the validity window, in seconds since the Unix epoch.
+    pub valid_after: u64,
+    /// End of the validity window, in seconds since the Unix epoch.
+    pub valid_before: u64,
+    /// Names of the critical options.
+    pub critical_options: Vec<String>,
+    /// Names of the extensions.
+    pub extensions: Vec<String>,
+    /// The CA key that signed the certificate.
+    pub signature_key: PublicKey,
+}
+
+impl Certificate {
+    /// Whether the certificate is valid at `now` (seconds since the Unix epoch).
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_after <= now && now < self.valid_before
+    }
+
+    /// Validity window in the style of `ssh-keygen -L`.
+    pub fn validity(&self) -> String {
+        if self.valid_after == 0 && self.valid_before == u64::MAX {
+            "valid forever".to_string()
+        } else {
+            format!(
+                "valid from {} to {}",
+                format_timestamp(self.valid_after),
+                format_timestamp(self.valid_before)
+            )
+        }
+    }
+}
+
+fn read_certificate(reader: &mut WireReader, base_algorithm: &str) -> Result<Certificate, KeyParseError> {
+    let _nonce = reader.read_string()?;
+
+    // Keep the raw key fields so the plain key blob can be rebuilt for matching.
+    let fields_start = reader.remaining();
+    let data = read_key_data(reader, base_algorithm)?;
+    if data == KeyData::Other {
+        return Err(KeyParseError::InvalidKey(format!(
+            "unsupported certificate key type {}",
+            base_algorithm
+        )));
+    }
+    let fields = &fields_start[..fields_start.len() - reader.remaining().len()];
+    let mut key_blob = Vec::with_capacity(4 + base_algorithm.len() + fields.len());
+    key_blob.extend_from_slice(&(base_algorithm.len() as u32).to_be_bytes());
+    key_blob.extend_from_slice(base_algorithm.as_bytes());
+    key_blob.extend_from_slice(fields);
+
+    let serial = reader.read_u64()?;
+    let cert_type = match reader.read_u32()? {
+        1 => CertType::User,
+        2 => CertType::Host,
+        other => {
+            return Err(KeyParseError::InvalidKey(format!(
+                "unknown certificate type {}",
+                other
+            )));
+        }
+    };
+    let key_id = reader.read_str()?.to_string();
+
+    let mut principals = Vec::new();
+    let mut principal_reader = WireReader::new(reader.read_string()?);
+    while !principal_reader.remaining().is_empty() {
+        principals.push(principal_reader.read_str()?.to_string());
+    }
+
+    let valid_after = reader.read_u64()?;
+    let valid_before = reader.read_u64()?;
+    let critical_options = read_option_names(reader.read_string()?)?;
+    let extensions = read_option_names(reader.read_string()?)?;
+    let _reserved = reader.read_string()?;
+    let signature_key = PublicKey::parse(reader.read_string()?)?;
+    let _signature = reader.read_string()?;
+
+    Ok(Certificate {
+        key: PublicKey {
+            algorithm: base_algorithm.to_string(),
+            data,
+        },
+        key_blob,
+        serial,
+        cert_type,
+        key_id,
+        principals,
+        valid_after,
+        valid_before,
+        critical_options,
+        extensions,
+        signature_key,
+    })
+}
+
+/// Read the names from a packed list of `(name, data)` certificate options.
+fn read_option_names(buf: &[u8]) -> Result<Vec<String>, KeyParseError> {
+    let mut reader = WireReader::new(buf);
+    let mut names = Vec::new();
+    while !reader.remaining().is_empty() {
+        names.push(reader.read_str()?.to_string());
+        let _data = reader.read_string()?;
+    }
+    Ok(names)
+}
+
+/// Format seconds since the Unix epoch as a UTC `YYYY-MM-DDTHH:MM:SS` timestamp.
+pub(crate) fn format_timestamp(secs: u64) -> String {
+    if secs == u64::MAX {
+        return "forever".to_string();
+    }
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn read_ed25519_key(reader: &mut WireReader) -> Result<Vec<u8>, KeyParseError> {
+    let key = reader.read_string()?;
+    if key.len() != 32 {
+        return Err(KeyParseError::InvalidKey(format!(
+            "Ed25519 key is {} bytes, expected 32",
+            key.len()
+        )));
+    }
+    Ok(key.to_vec())
+}
+
+fn read_ecdsa_point(
+    reader: &mut WireReader,
+    expected_curve: &str,
+) -> Result<(EcdsaCurve, Vec<u8>), KeyParseError> {
+    let curve = EcdsaCurve::from_name(expected_curve)
+        .ok_or_else(|| KeyParseError::UnknownCurve(expected_curve.to_string()))?;
+    let found = reader.read_str()?;
+    if found != expected_curve {
+        return Err(KeyParseError::CurveMismatch {
+            expected: expected_curve.to_string(),
+            found: found.to_string(),
+        });
+    }
+
+    // Only uncompressed points (0x04 || x || y) are valid in SSH keys.
+    let point = reader.read_string()?;
+    if point.len() != 1 + 2 * curve.coordinate_len() || point[0] != 0x04 {
+        return Err(KeyParseError::InvalidKey(format!(
+            "invalid {} point encoding",
+            curve
+        )));
+    }
+    Ok((curve, point.to_vec()))
+}
+
+/// Number of significant bits in a big-endian unsigned mpint.
+fn mpint_bits(value: &[u8]) -> u32 {
+    match value.iter().position(|&b| b != 0) {
+        Some(idx) => (value.len() - idx) as u32 * 8 - value[idx].leading_zeros(),
+        None => 0,
+    }
+}
+
+/// Reader for the SSH wire encoding (RFC 4251 section 5).
+pub(crate) struct WireReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], KeyParseError> {
+        if self.buf.len() < len {
+            return Err(KeyParseError::Truncated);
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, KeyParseError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64, KeyParseError> {
+        let b = self.take(8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(b);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub(crate) fn read_string(&mut self) -> Result<&'a [u8], KeyParseError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn read_str(&mut self) -> Result<&'a str, KeyParseError> {
+        std::str::from_utf8(self.read_string()?).map_err(|_| KeyParseError::InvalidUtf8)
+    }
+
+    /// Read an unsigned mpint, dropping the leading zero byte used to keep it positive.
+    pub(crate) fn read_mpint(&mut self) -> Result<Vec<u8>, KeyParseError> {
+        let value = self.read_string()?;
+        if value.first().is_some_and(|&b| b & 0x80 != 0) {
+            return Err(KeyParseError::InvalidKey("negative mpint in key".to_string()));
+        }
+        let start = value.iter().position(|&b| b != 0).unwrap_or(value.len());
+        Ok(value[start..].to_vec())
+    }
+
+    /// The bytes not read yet.
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Fail if any bytes are left unread.
+    pub(crate) fn finish(&self) -> Result<(), KeyParseError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(KeyParseError::TrailingData(self.buf.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{ED25519_CERT, ED25519_KEY, ed25519_blob, put_string};
+    use base64::{Engine as _, engine::general_purpose};
+
+    fn ecdsa_blob(algorithm_curve: &str, point_curve: &str) -> Vec<u8> {
+        let mut blob = Vec::new();
+        put_string(&mut blob, format!("ecdsa-sha2-{}", algorithm_curve).as_bytes());
+        put_string(&mut blob, point_curve.as_bytes());
+        let mut point = vec![0x04];
+        point.extend_from_slice(&[1; 64]);
+        put_string(&mut blob, &point);
+        blob
+    }
+
+    #[test]
+    fn parses_ed25519() {
+        let key = PublicKey::parse(&ed25519_blob(7)).unwrap();
+        assert_eq!(key.algorithm, "ssh-ed25519");
+        assert_eq!(key.data, KeyData::Ed25519 { key: vec![7; 32] });
+        assert_eq!(key.bits(), Some(256));
+        assert_eq!(key.summary(), "ED25519");
+    }
+
+    #[test]
+    fn parses_ecdsa() {
+        let key = PublicKey::parse(&ecdsa_blob("nistp256", "nistp256")).unwrap();
+        assert_eq!(key.curve(), Some(EcdsaCurve::NistP256));
+        assert_eq!(key.summary(), "ECDSA nistp256");
+    }
+
+    #[test]
+    fn parses_rsa_bits_without_sign_byte() {
+        let mut blob = Vec::new();
+        put_string(&mut blob, b"ssh-rsa");
+        put_string(&mut blob, &[0x01, 0x00, 0x01]);
+        let mut modulus = vec![0x00, 0xc0];
+        modulus.extend_from_slice(&[0; 255]);
+        put_string(&mut blob, &modulus);
+        let key = PublicKey::parse(&blob).unwrap();
+        assert_eq!(key.bits(), Some(2048));
+        assert_eq!(key.summary(), "RSA 2048");
+    }
+
+    #[test]
+    fn rejects_truncated_blob() {
+        let blob = ed25519_blob(7);
+        assert_eq!(PublicKey::parse(&blob[..blob.len() - 1]), Err(KeyParseError::Truncated));
+        // Cut inside the length prefix of the key field.
+        assert_eq!(PublicKey::parse(&blob[..17]), Err(KeyParseError::Truncated));
+        assert_eq!(PublicKey::parse(&[]), Err(KeyParseError::Truncated));
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut blob = ed25519_blob(7);
+        blob.extend_from_slice(&[0, 0]);
+        assert_eq!(PublicKey::parse(&blob), Err(KeyParseError::TrailingData(2)));
+
+        let mut blob = ecdsa_blob("nistp256", "nistp256");
+        blob.push(0);
+        assert_eq!(PublicKey::parse(&blob), Err(KeyParseError::TrailingData(1)));
+    }
+
+    #[test]
+    fn rejects_bad_key_fields() {
+        let mut blob = Vec::new();
+        put_string(&mut blob, b"ssh-ed25519");
+        put_string(&mut blob, &[7; 31]);
+        assert!(matches!(PublicKey::parse(&blob), Err(KeyParseError::InvalidKey(_))));
+
+        assert_eq!(
+            PublicKey::parse(&ecdsa_blob("nistp256", "nistp384")),
+            Err(KeyParseError::CurveMismatch {
+                expected: "nistp256".to_string(),
+                found: "nistp384".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn accepts_unknown_algorithms_unread() {
+        let mut blob = Vec::new();
+        put_string(&mut blob, b"ssh-future@example.com");
+        blob.extend_from_slice(b"anything");
+        let key = PublicKey::parse(&blob).unwrap();
+        assert_eq!(key.data, KeyData::Other);
+        assert_eq!(key.bits(), None);
+    }
+
+    #[test]
+    fn parses_certificate() {
+        let blob = general_purpose::STANDARD.decode(ED25519_CERT).unwrap();
+        let key = PublicKey::parse(&blob).unwrap();
+        assert_eq!(key.algorithm, "ssh-ed25519-cert-v01@openssh.com");
+        let certificate = key.certificate().unwrap();
+        assert_eq!(certificate.key.algorithm, "ssh-ed25519");
+        assert_eq!(
+            certificate.key_blob,
+            general_purpose::STANDARD.decode(ED25519_KEY).unwrap()
+        );
+        assert_eq!(certificate.cert_type, CertType::User);
+        assert_eq!(certificate.key_id, "dev-cert");
+        assert_eq!(certificate.principals, ["dev", "root"]);
+        assert_eq!(certificate.extensions.len(), 5);
+        assert!(certificate.critical_options.is_empty());
+        assert_eq!(certificate.signature_key.algorithm, "ssh-ed25519");
+        assert!(!certificate.is_valid_at(1792240379));
+        assert!(certificate.is_valid_at(1792240380));
+        assert!(!certificate.is_valid_at(1823690089));
+    }
+}
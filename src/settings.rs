@@ -0,0 +1,160 @@
+use crate::config::{GlobalDefaults, HostConflictPolicy, IdentityAgentMode, KeyFileNaming};
+use crate::policy::KeyPolicy;
+use crate::types::FingerprintHash;
+use std::path::{Path, PathBuf};
+
+/// Persisted user settings shared by the CLI and the tray app.
+///
+/// Stored as `key = value` lines in [`settings_file_path`](crate::constants::settings_file_path).
+/// Blank lines and lines starting with `#` are ignored, and list settings
+/// such as `agent_socket` or `allowed_algorithm` may be repeated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// Agent sockets to read keys from, merged in order. Empty uses `SSH_AUTH_SOCK`.
+    pub agent_sockets: Vec<PathBuf>,
+    /// Whether and how to emit `IdentityAgent` for each generated host.
+    pub identity_agent: IdentityAgentMode,
+    /// Hash used when printing fingerprints and in the generated config.
+    pub fingerprint_hash: FingerprintHash,
+    /// Rules deciding which keys may be synced.
+    pub policy: KeyPolicy,
+    /// Template for the directives in each host block.
+    pub host_template: Option<PathBuf>,
+    /// The `Host *` block after the host blocks of the generated config.
+    pub global_defaults: GlobalDefaults,
+    /// What to do when two keys produce a `Host` block for the same host.
+    pub host_conflict: HostConflictPolicy,
+    /// How key files are named.
+    pub key_file_naming: KeyFileNaming,
+}
+
+impl Settings {
+    /// Load settings from the default settings file.
+    ///
+    /// A missing file yields the default settings.
+    pub fn load() -> Result<Self, String> {
+        let path = crate::constants::settings_file_path()
+            .ok_or_else(|| "Failed to determine settings file path".to_string())?;
+        Self::load_from(&path)
+    }
+
+    /// Load settings from `path`. A missing file yields the default settings.
+    pub fn load_from(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read settings file {}: {}", path.display(), e)
+        })?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Save settings to the default settings file, creating its folder if needed.
+    pub fn save(&self) -> Result<(), String> {
+        let path = crate::constants::settings_file_path()
+            .ok_or_else(|| "Failed to determine settings file path".to_string())?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                format!("Failed to create settings folder {}: {}", parent.display(), e)
+            })?;
+        }
+        crate::fsutil::write_atomic(&path, self.to_file_content().as_bytes(), 0o600).map_err(|e| {
+            format!("Failed to write settings file {}: {}", path.display(), e)
+        })
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut settings = Self::default();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected `key = value`", idx + 1))?;
+            let value = value.trim();
+            match key.trim() {
+                "agent_socket" => settings.agent_sockets.extend(non_empty(value).map(expand_tilde)),
+                "identity_agent" => {
+                    settings.identity_agent =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                "fingerprint_hash" => {
+                    settings.fingerprint_hash =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                "min_rsa_bits" => {
+                    settings.policy.min_rsa_bits = Some(
+                        value
+                            .parse()
+                            .map_err(|e| format!("line {}: invalid bit count: {}", idx + 1, e))?,
+                    )
+                }
+                "allowed_algorithm" => {
+                    settings.policy.allowed_algorithms.extend(non_empty(value).map(String::from))
+                }
+                "deny_comment" => {
+                    settings.policy.denied_comments.extend(non_empty(value).map(String::from))
+                }
+                "policy_action" => {
+                    settings.policy.action =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                "host_template" => settings.host_template = non_empty(value).map(expand_tilde),
+                "global_defaults" => {
+                    settings.global_defaults =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                "host_conflict" => {
+                    settings.host_conflict =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                "key_file_naming" => {
+                    settings.key_file_naming =
+                        value.parse().map_err(|e| format!("line {}: {}", idx + 1, e))?
+                }
+                other => return Err(format!("line {}: unknown setting `{}`", idx + 1, other)),
+            }
+        }
+        Ok(settings)
+    }
+
+    fn to_file_content(&self) -> String {
+        let mut content = format!("# {} settings\n", crate::constants::PROGRAM_NAME);
+        for socket in &self.agent_sockets {
+            content.push_str(&format!("agent_socket = {}\n", socket.display()));
+        }
+        content.push_str(&format!("identity_agent = {}\n", self.identity_agent));
+        content.push_str(&format!("fingerprint_hash = {}\n", self.fingerprint_hash));
+        if let Some(bits) = self.policy.min_rsa_bits {
+            content.push_str(&format!("min_rsa_bits = {}\n", bits));
+        }
+        for algorithm in &self.policy.allowed_algorithms {
+            content.push_str(&format!("allowed_algorithm = {}\n", algorithm));
+        }
+        for pattern in &self.policy.denied_comments {
+            content.push_str(&format!("deny_comment = {}\n", pattern));
+        }
+        content.push_str(&format!("policy_action = {}\n", self.policy.action));
+        if let Some(ref path) = self.host_template {
+            content.push_str(&format!("host_template = {}\n", path.display()));
+        }
+        content.push_str(&format!("global_defaults = {}\n", self.global_defaults));
+        content.push_str(&format!("host_conflict = {}\n", self.host_conflict));
+        content.push_str(&format!("key_file_naming = {}\n", self.key_file_naming));
+        content
+    }
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/// Expand a leading `~/` to the user's home directory.
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,296 @@
+use std::path::Path;
+
+/// One `Keyword arguments` line of an ssh_config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLine {
+    /// 1-based line number in the file.
+    pub line_number: usize,
+    /// The keyword as written; ssh compares keywords ignoring case.
+    pub keyword: String,
+    /// The arguments with quotes and escapes removed.
+    pub args: Vec<String>,
+    /// The arguments as written, without a trailing comment.
+    pub value: String,
+}
+
+impl ConfigLine {
+    /// Whether this line's keyword is `keyword`, ignoring case.
+    pub fn is(&self, keyword: &str) -> bool {
+        self.keyword.eq_ignore_ascii_case(keyword)
+    }
+}
+
+/// Which hosts the directives of a section apply to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// Directives before the first `Host` or `Match` line.
+    Global,
+    /// A `Host` line's patterns.
+    Host(Vec<String>),
+    /// A `Match` line's criteria.
+    Match(Vec<String>),
+}
+
+impl Scope {
+    /// Whether the section applies to every host: the global section, a
+    /// `Host` line with `*` and no negation, or `Match all`.
+    pub fn applies_to_all(&self) -> bool {
+        match self {
+            Scope::Global => true,
+            Scope::Host(patterns) => {
+                patterns.iter().any(|pattern| pattern == "*")
+                    && !patterns.iter().any(|pattern| pattern.starts_with('!'))
+            }
+            Scope::Match(criteria) => {
+                criteria.len() == 1 && criteria[0].eq_ignore_ascii_case("all")
+            }
+        }
+    }
+}
+
+/// The directives under one `Host` or `Match` line, or before the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSection {
+    pub scope: Scope,
+    /// Line number of the `Host` or `Match` line, 0 for the global section.
+    pub line_number: usize,
+    pub directives: Vec<ConfigLine>,
+}
+
+/// An ssh_config file split into sections, following ssh_config(5): `#`
+/// comments, `Keyword value` or `Keyword=value`, double or single quoted
+/// arguments, and keywords that ignore case. `Include` is not followed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshConfig {
+    /// Sections in file order. The first is always the global section.
+    pub sections: Vec<ConfigSection>,
+}
+
+impl SshConfig {
+    /// Parse the file at `path`. A missing file yields `None`.
+    pub fn load_from(path: &Path) -> Result<Option<Self>, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        Self::parse(&content).map(Some).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse ssh_config text.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut sections = vec![ConfigSection {
+            scope: Scope::Global,
+            line_number: 0,
+            directives: Vec::new(),
+        }];
+        for (idx, line) in content.lines().enumerate() {
+            let Some(line) = parse_line(idx + 1, line).map_err(|e| format!("line {}: {}", idx + 1, e))?
+            else {
+                continue;
+            };
+            let scope = if line.is("host") {
+                Scope::Host(line.args)
+            } else if line.is("match") {
+                Scope::Match(line.args)
+            } else {
+                // The global section is always there, so there is a last one.
+                if let Some(section) = sections.last_mut() {
+                    section.directives.push(line);
+                }
+                continue;
+            };
+            sections.push(ConfigSection {
+                scope,
+                line_number: line.line_number,
+                directives: Vec::new(),
+            });
+        }
+        Ok(Self { sections })
+    }
+
+    /// Directives before the first `Host` or `Match` line.
+    pub fn global(&self) -> &[ConfigLine] {
+        &self.sections[0].directives
+    }
+
+    /// Every directive with the scope it is in, in file order.
+    pub fn directives(&self) -> impl Iterator<Item = (&Scope, &ConfigLine)> {
+        self.sections
+            .iter()
+            .flat_map(|section| section.directives.iter().map(move |line| (&section.scope, line)))
+    }
+}
+
+/// Parse a single line numbered `line_number`. Blank lines and comments yield `None`.
+pub fn parse_line(line_number: usize, line: &str) -> Result<Option<ConfigLine>, String> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (keyword, rest) = split_keyword(line);
+    if keyword.is_empty() {
+        return Err("missing keyword".to_string());
+    }
+    let (args, value_len) = split_args(rest)?;
+    if args.is_empty() {
+        return Err(format!("`{}` has no value", keyword));
+    }
+    Ok(Some(ConfigLine {
+        line_number,
+        keyword: keyword.to_string(),
+        args,
+        value: rest[..value_len].trim_end().to_string(),
+    }))
+}
+
+/// Split a line into keyword and the rest, allowing `Keyword value` and
+/// `Keyword=value`.
+fn split_keyword(line: &str) -> (&str, &str) {
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+    (keyword, rest.trim())
+}
+
+/// Split arguments the way ssh does: on whitespace, with `"` or `'` quotes
+/// grouping words, `\` escaping a quote, backslash or space, and an unquoted
+/// word starting with `#` beginning a comment.
+///
+/// Returns the arguments and the length of `rest` before any comment.
+fn split_args(rest: &str) -> Result<(Vec<String>, usize), String> {
+    let mut args = Vec::new();
+    let mut chars = rest.char_indices().peekable();
+    loop {
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let Some(&(start, first)) = chars.peek() else {
+            return Ok((args, rest.len()));
+        };
+        if first == '#' {
+            return Ok((args, start));
+        }
+
+        let mut arg = String::new();
+        let mut quote: Option<char> = None;
+        while let Some(&(_, c)) = chars.peek() {
+            if quote.is_none() && c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            match c {
+                '\\' => match chars.peek() {
+                    Some(&(_, next))
+                        if next == '"' || next == '\'' || next == '\\' || (quote.is_none() && next == ' ') =>
+                    {
+                        arg.push(next);
+                        chars.next();
+                    }
+                    _ => arg.push(c),
+                },
+                '"' | '\'' if quote.is_none() => quote = Some(c),
+                c if Some(c) == quote => quote = None,
+                c => arg.push(c),
+            }
+        }
+        if let Some(quote) = quote {
+            return Err(format!("unterminated {} quote", quote));
+        }
+        args.push(arg);
+    }
+}
+
+/// Split a line the tool wrote itself into keyword and value, without
+/// interpreting quotes or comments.
+pub(crate) fn split_directive(line: &str) -> (&str, &str) {
+    split_keyword(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> ConfigLine {
+        parse_line(1, text).unwrap().unwrap()
+    }
+
+    #[test]
+    fn keyword_forms() {
+        for text in ["Port 22", "Port=22", "Port = 22", "  Port\t22  "] {
+            let parsed = line(text);
+            assert_eq!(parsed.keyword, "Port");
+            assert_eq!(parsed.args, ["22"]);
+            assert_eq!(parsed.value, "22");
+        }
+        assert!(line("PORT 22").is("port"));
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert_eq!(parse_line(1, "").unwrap(), None);
+        assert_eq!(parse_line(1, "   # Include foo").unwrap(), None);
+        let parsed = line("User alice # the usual");
+        assert_eq!(parsed.args, ["alice"]);
+        assert_eq!(parsed.value, "alice");
+        // `#` only starts a comment at the beginning of a word.
+        assert_eq!(line("User al#ice").args, ["al#ice"]);
+        assert_eq!(line("User \"#alice\"").args, ["#alice"]);
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        let parsed = line(r#"IdentityFile "/a b/c" 'd e' f\ g h\"i"#);
+        assert_eq!(parsed.args, ["/a b/c", "d e", "f g", "h\"i"]);
+        assert_eq!(line(r#"IdentityFile "a\\b\"c""#).args, [r#"a\b"c"#]);
+        assert_eq!(line(r#"Path a"b c"d"#).args, ["ab cd"]);
+        // A backslash before any other character is kept, but `\\` still becomes one.
+        assert_eq!(line(r"IdentityAgent \\.\pipe\agent").args, [r"\.\pipe\agent"]);
+    }
+
+    #[test]
+    fn rejects_broken_lines() {
+        assert!(parse_line(1, "User \"alice").is_err());
+        assert!(parse_line(1, "User").is_err());
+        assert!(parse_line(1, "User # nobody").is_err());
+        assert!(parse_line(1, "=value").is_err());
+    }
+
+    #[test]
+    fn sections() {
+        let config = SshConfig::parse(
+            "Include a\n\nHost *.example !skip.example\n  User one\n\
+             match all\n  Port 2\nHost *\n  Port 3\n",
+        )
+        .unwrap();
+        assert_eq!(config.global().len(), 1);
+        assert_eq!(config.sections.len(), 4);
+        assert_eq!(
+            config.sections[1].scope,
+            Scope::Host(vec!["*.example".to_string(), "!skip.example".to_string()])
+        );
+        assert_eq!(config.sections[1].line_number, 3);
+
+        let applies: Vec<_> = config
+            .directives()
+            .map(|(scope, line)| (line.keyword.as_str(), scope.applies_to_all()))
+            .collect();
+        assert_eq!(applies, [("Include", true), ("User", false), ("Port", true), ("Port", true)]);
+    }
+
+    #[test]
+    fn scope_applies_to_all() {
+        assert!(Scope::Host(vec!["*".to_string()]).applies_to_all());
+        assert!(!Scope::Host(vec!["*".to_string(), "!a".to_string()]).applies_to_all());
+        assert!(!Scope::Host(vec!["*.example".to_string()]).applies_to_all());
+        assert!(Scope::Match(vec!["ALL".to_string()]).applies_to_all());
+        assert!(!Scope::Match(vec!["host".to_string(), "a".to_string()]).applies_to_all());
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        let error = SshConfig::parse("Host a\n\n  User \"bob\n").unwrap_err();
+        assert!(error.starts_with("line 3:"), "{}", error);
+    }
+}
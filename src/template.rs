@@ -0,0 +1,213 @@
+use crate::keywords::is_ssh_config_keyword;
+use crate::ssh_config::{parse_line, split_directive};
+use std::path::Path;
+
+/// Placeholders a host template may use.
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &[
+    "name",
+    "user",
+    "host",
+    "hostname",
+    "port",
+    "key_path",
+    "certificate_path",
+    "identity_agent",
+    "proxy_jump",
+    "fingerprint",
+    "key_type",
+];
+
+/// The built-in template, matching the blocks written without one.
+pub const DEFAULT_HOST_TEMPLATE: &str = "\
+HostName {{hostname}}
+User {{user}}
+Port {{port}}
+IdentityFile {{key_path}}
+CertificateFile {{certificate_path}}
+IdentityAgent {{identity_agent}}
+ProxyJump {{proxy_jump}}
+";
+
+/// The directives written inside each generated `Host` block.
+///
+/// Each line is a `Keyword value` directive that may use `{{placeholder}}`
+/// fields from [`TEMPLATE_PLACEHOLDERS`]. A line whose placeholders are all
+/// empty for a block is left out, so `Port {{port}}` only appears for keys
+/// with a port. The `Host` line, the key's comment options, overrides and
+/// the final `IdentitiesOnly yes` are always added around the template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostTemplate {
+    lines: Vec<String>,
+}
+
+impl Default for HostTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_HOST_TEMPLATE).expect("built-in host template is valid")
+    }
+}
+
+impl HostTemplate {
+    /// Load a template from `path`.
+    pub fn load_from(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!("Failed to read host template {}: {}", path.display(), e)
+        })?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse a template, checking keywords and placeholder names.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut lines = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let Some(parsed) = parse_line(idx + 1, line).map_err(|e| format!("line {}: {}", idx + 1, e))?
+            else {
+                continue;
+            };
+            let keyword = parsed.keyword.as_str();
+            if !is_ssh_config_keyword(keyword) {
+                return Err(format!("line {}: unknown ssh_config keyword `{}`", idx + 1, keyword));
+            }
+            // IdentitiesOnly is always written last, and the rest would end the block.
+            if ["host", "match", "include", "identitiesonly"]
+                .contains(&keyword.to_ascii_lowercase().as_str())
+            {
+                return Err(format!("line {}: `{}` can't be used in a host template", idx + 1, keyword));
+            }
+            // Placeholders are quoted as needed when filled in, so the template can't quote them.
+            if parsed.value.contains('"') {
+                return Err(format!("line {}: quotes aren't supported in values", idx + 1));
+            }
+            // Drop any trailing comment, which rendering wouldn't recognize.
+            let line = format!("{} {}", parsed.keyword, parsed.value);
+            for (_, placeholder) in placeholders(&line)? {
+                if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+                    return Err(format!("line {}: unknown placeholder `{{{{{}}}}}`", idx + 1, placeholder));
+                }
+            }
+            lines.push(line);
+        }
+        Ok(Self { lines })
+    }
+
+    /// The template's directive lines, before filling in placeholders.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Fill in the template for one block, returning its `(keyword, value)`
+    /// directives.
+    ///
+    /// `value` gives the text for each placeholder, empty if it doesn't apply.
+    /// Placeholders are filled in a single pass, so a value that itself
+    /// contains `{{...}}` is written as is.
+    pub fn render(&self, value: impl Fn(&str) -> String) -> Vec<(String, String)> {
+        let mut rendered = Vec::new();
+        for line in &self.lines {
+            // Placeholders were checked when parsing.
+            let fields = placeholders(line).unwrap_or_default();
+            let values: Vec<String> = fields.iter().map(|(_, name)| value(name)).collect();
+            if !fields.is_empty() && values.iter().all(String::is_empty) {
+                continue;
+            }
+            let mut out = String::new();
+            let mut rest = line.as_str();
+            for ((field, _), value) in fields.iter().zip(&values) {
+                // Fields are in line order, so each one is in what's left of the line.
+                if let Some((before, after)) = rest.split_once(field) {
+                    out.push_str(before);
+                    out.push_str(value);
+                    rest = after;
+                }
+            }
+            out.push_str(rest);
+            let (keyword, value) = split_directive(&out);
+            rendered.push((keyword.to_string(), value.to_string()));
+        }
+        rendered
+    }
+}
+
+/// The `{{placeholder}}` fields in `line` with their names, in order.
+fn placeholders(line: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut fields = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end + 2)
+            .ok_or_else(|| format!("unclosed `{{{{` in `{}`", line))?;
+        let field = &rest[start..end];
+        fields.push((field, field[2..field.len() - 2].trim()));
+        rest = &rest[end..];
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &HostTemplate, values: &[(&str, &str)]) -> Vec<String> {
+        template
+            .render(|name| {
+                values
+                    .iter()
+                    .find(|(placeholder, _)| *placeholder == name)
+                    .map(|(_, value)| value.to_string())
+                    .unwrap_or_default()
+            })
+            .iter()
+            .map(|(keyword, value)| format!("{} {}", keyword, value))
+            .collect()
+    }
+
+    #[test]
+    fn default_template_skips_empty_lines() {
+        let lines = render(&HostTemplate::default(), &[("user", "git"), ("key_path", "/k/github")]);
+        assert_eq!(lines, ["User git", "IdentityFile /k/github"]);
+    }
+
+    #[test]
+    fn fills_several_placeholders() {
+        let template = HostTemplate::parse("# comment\nSetEnv KEY={{key_type}}-{{name}}\nUser=me\n").unwrap();
+        assert_eq!(
+            render(&template, &[("key_type", "ssh-ed25519"), ("name", "box")]),
+            ["SetEnv KEY=ssh-ed25519-box", "User me"]
+        );
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let template = HostTemplate::parse("HostName {{hostname}}\nUser {{user}}\n").unwrap();
+        let lines = render(&template, &[("hostname", "{{user}}"), ("user", "{{hostname}}")]);
+        assert_eq!(lines, ["HostName {{user}}", "User {{hostname}}"]);
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert_eq!(
+            HostTemplate::parse("User {{user}}\nHostName {{address}}\n").unwrap_err(),
+            "line 2: unknown placeholder `{{address}}`"
+        );
+        assert_eq!(
+            HostTemplate::parse("User {{user\n").unwrap_err(),
+            "unclosed `{{` in `User {{user`"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(
+            HostTemplate::parse("Colour {{user}}\n").unwrap_err(),
+            "line 1: unknown ssh_config keyword `Colour`"
+        );
+        assert_eq!(
+            HostTemplate::parse("IdentitiesOnly no\n").unwrap_err(),
+            "line 1: `IdentitiesOnly` can't be used in a host template"
+        );
+        assert_eq!(
+            HostTemplate::parse("IdentityFile \"{{key_path}}\"\n").unwrap_err(),
+            "line 1: quotes aren't supported in values"
+        );
+    }
+}
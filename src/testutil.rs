@@ -0,0 +1,54 @@
+//! Fixtures shared by the unit tests.
+
+/// Append `bytes` as an SSH wire string, with a 4 byte length prefix.
+pub(crate) fn put_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// An Ed25519 public key blob in SSH wire format, distinct for each `seed`.
+pub(crate) fn ed25519_blob(seed: u8) -> Vec<u8> {
+    let mut blob = Vec::new();
+    put_string(&mut blob, b"ssh-ed25519");
+    put_string(&mut blob, &[seed; 32]);
+    blob
+}
+
+/// An Ed25519 public key, base64 encoded as in a `.pub` file.
+pub(crate) const ED25519_KEY: &str =
+    "AAAAC3NzaC1lZDI1NTE5AAAAIN89irIsU44wOipsa0ffNYzD4QDH+sVbehtsZQWn03VP";
+
+/// A user certificate for [`ED25519_KEY`] with key id `dev-cert`, principals
+/// `dev` and `root`, valid from 1792240380 to 1823690089.
+pub(crate) const ED25519_CERT: &str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIK3fhJGm1zluwO3Giqu859TbQzGf7J9d71DZayVsAiKyAAAAIN89irIsU44wOipsa0ffNYzD4QDH+sVbehtsZQWn03VPAAAAAAAAAAAAAAABAAAACGRldi1jZXJ0AAAADwAAAANkZXYAAAAEcm9vdAAAAABq02r8AAAAAGyzTWkAAAAAAAAAggAAABVwZXJtaXQtWDExLWZvcndhcmRpbmcAAAAAAAAAF3Blcm1pdC1hZ2VudC1mb3J3YXJkaW5nAAAAAAAAABZwZXJtaXQtcG9ydC1mb3J3YXJkaW5nAAAAAAAAAApwZXJtaXQtcHR5AAAAAAAAAA5wZXJtaXQtdXNlci1yYwAAAAAAAAAAAAAAMwAAAAtzc2gtZWQyNTUxOQAAACDpk/+D33zyxN4M+7WXRQF/b0l2TGIo8MCSKmVpe2Ki0QAAAFMAAAALc3NoLWVkMjU1MTkAAABAbYvz/5PhlqlxmbF3J1SYWTzeAN3ZuaBd0lTmYoye+BYvERHxg4MWDsXFkpfuaj389mnjWh0YLDwdM8tBZ0LKAg==";
+
+/// A uniquely named directory under the system temp dir, removed on drop.
+pub(crate) struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ssh-agent-sync-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("create temp dir");
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+
+    /// Use this directory as the SSH directory for the rest of the test.
+    pub(crate) fn use_as_ssh_dir(&self) {
+        crate::constants::TEST_SSH_DIR.with(|dir| *dir.borrow_mut() = Some(self.0.clone()));
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
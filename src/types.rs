@@ -1,9 +1,212 @@
+use crate::pubkey::{KeyParseError, PublicKey};
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// The agent socket a key was read from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AgentSocket {
+    /// The agent named by `SSH_AUTH_SOCK` at the time of the sync.
+    Env(PathBuf),
+    /// An explicitly selected agent socket.
+    Path(PathBuf),
+}
+
+impl AgentSocket {
+    pub fn path(&self) -> &Path {
+        match self {
+            AgentSocket::Env(path) | AgentSocket::Path(path) => path,
+        }
+    }
+}
+
+/// Hash used to compute key fingerprints, matching `ssh-keygen -E`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FingerprintHash {
+    /// `SHA256:<base64>`, the OpenSSH default.
+    #[default]
+    Sha256,
+    /// `MD5:<hex:pairs>`, the legacy format.
+    Md5,
+}
+
+impl std::str::FromStr for FingerprintHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(FingerprintHash::Sha256),
+            "md5" => Ok(FingerprintHash::Md5),
+            _ => Err(format!("expected sha256 or md5, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for FingerprintHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FingerprintHash::Sha256 => "sha256",
+            FingerprintHash::Md5 => "md5",
+        })
+    }
+}
+
+/// Compute the fingerprint of a public key blob in the same format as `ssh-keygen -l`.
+pub fn fingerprint(blob: &[u8], hash: FingerprintHash) -> String {
+    match hash {
+        FingerprintHash::Sha256 => format!(
+            "SHA256:{}",
+            general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(blob))
+        ),
+        FingerprintHash::Md5 => {
+            let hex: Vec<String> = Md5::digest(blob).iter().map(|b| format!("{:02x}", b)).collect();
+            format!("MD5:{}", hex.join(":"))
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SshKey {
+    /// The comment the key had in the agent.
+    pub comment: String,
     pub name: Option<String>,
     pub user: String,
     pub host: String,
+    /// `Port` from a `user@host:port` or `port=` comment.
+    pub port: Option<u16>,
+    /// Hosts negated with `!` in the comment, added to the `Host` line.
+    pub negated_hosts: Vec<String>,
+    /// `ProxyJump` from a `via=` or `jump=` comment option.
+    pub proxy_jump: Option<String>,
+    /// Extra `key=value` ssh options from the comment, in order.
+    pub options: Vec<(String, String)>,
     pub key_type: String,
     pub key: String,
     pub file_name: String,
-}
\ No newline at end of file
+    /// Socket of the agent the key was read from, if it came from an agent.
+    pub agent: Option<AgentSocket>,
+    /// File name of the certificate for this key, if the agent also holds one.
+    pub certificate_file: Option<String>,
+}
+
+impl SshKey {
+    /// Whether this is an OpenSSH certificate rather than a plain key.
+    pub fn is_certificate(&self) -> bool {
+        self.key_type.ends_with("-cert-v01@openssh.com")
+    }
+
+    /// The raw public key blob.
+    pub fn blob(&self) -> Vec<u8> {
+        general_purpose::STANDARD.decode(&self.key).unwrap_or_default()
+    }
+
+    /// Parse the public key blob, e.g. to inspect its size or curve.
+    pub fn public_key(&self) -> Result<PublicKey, KeyParseError> {
+        PublicKey::parse(&self.blob())
+    }
+
+    /// The key fingerprint using the given hash.
+    pub fn fingerprint(&self, hash: FingerprintHash) -> String {
+        fingerprint(&self.blob(), hash)
+    }
+}
+
+/// Why an identity from the agent was not turned into an `SshKey`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The comment has no `@` separating user and host.
+    NoAt,
+    /// The comment has more than one `@`.
+    MultipleAt,
+    /// The `user@host` part contains whitespace.
+    Whitespace,
+    /// The comment has a `<` without a matching `>` (or vice versa), or the
+    /// chevrons are empty or out of order.
+    UnbalancedChevrons,
+    /// The user or host part is empty.
+    EmptyUserOrHost,
+    /// The port after `host:` (or in `port=`) is not a number from 1 to 65535.
+    InvalidPort(String),
+    /// The host has unbalanced brackets or junk after `[...]`.
+    InvalidHost(String),
+    /// A word after `user@host` is not a usable `key=value` option.
+    InvalidOption(String),
+    /// A field that would be written to the ssh config contains a quote, a
+    /// leading `#` or a control character.
+    UnsafeValue { field: String, value: String },
+    /// The host list has an empty entry, unbalanced braces or too many hosts.
+    InvalidTargets(String),
+    /// The public key blob could not be parsed.
+    BadBlob(KeyParseError),
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NoAt => write!(f, "comment has no `@`, expected `user@host`"),
+            SkipReason::MultipleAt => write!(f, "comment has more than one `@`"),
+            SkipReason::Whitespace => write!(f, "`user@host` contains whitespace"),
+            SkipReason::UnbalancedChevrons => {
+                write!(f, "comment has unbalanced or empty `<` `>`")
+            }
+            SkipReason::EmptyUserOrHost => write!(f, "user or host is empty"),
+            SkipReason::InvalidPort(port) => write!(f, "invalid port `{}`", port),
+            SkipReason::InvalidHost(msg) => write!(f, "invalid host: {}", msg),
+            SkipReason::InvalidOption(msg) => write!(f, "invalid option {}", msg),
+            SkipReason::UnsafeValue { field, value } => write!(
+                f,
+                "{} {:?} contains a quote, a leading `#` or a control character",
+                field, value
+            ),
+            SkipReason::InvalidTargets(msg) => write!(f, "invalid host list: {}", msg),
+            SkipReason::BadBlob(msg) => write!(f, "invalid public key: {}", msg),
+        }
+    }
+}
+
+/// An identity that was read from a source but could not be synced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedIdentity {
+    pub comment: String,
+    /// Base64 encoded public key blob.
+    pub key: String,
+    pub reason: SkipReason,
+    /// Socket of the agent the identity was read from, if it came from an agent.
+    pub agent: Option<AgentSocket>,
+}
+
+/// Keys read from one or more sources, along with the identities that were skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgentKeys {
+    pub keys: Vec<SshKey>,
+    pub skipped: Vec<SkippedIdentity>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_match_ssh_keygen() {
+        let blob = general_purpose::STANDARD
+            .decode("AAAAC3NzaC1lZDI1NTE5AAAAIN89irIsU44wOipsa0ffNYzD4QDH+sVbehtsZQWn03VP")
+            .unwrap();
+        assert_eq!(
+            fingerprint(&blob, FingerprintHash::Sha256),
+            "SHA256:ydemjxdYilxr59kWQhTjH2CeIOQEtu/YkpDG9KtTfWs"
+        );
+        assert_eq!(
+            fingerprint(&blob, FingerprintHash::Md5),
+            "MD5:56:44:af:3e:83:04:cd:58:50:ea:30:18:de:a7:a6:bf"
+        );
+    }
+
+    #[test]
+    fn parses_fingerprint_hash() {
+        assert_eq!("SHA256".parse(), Ok(FingerprintHash::Sha256));
+        assert_eq!("md5".parse(), Ok(FingerprintHash::Md5));
+        assert!("sha1".parse::<FingerprintHash>().is_err());
+    }
+}